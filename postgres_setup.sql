@@ -1,15 +1,36 @@
 -- Database: chest_storage
 
+DROP TABLE IF EXISTS reservations;
 DROP TABLE IF EXISTS chest_items;
 DROP TABLE IF EXISTS chests;
+DROP TABLE IF EXISTS item_tags;
+DROP TABLE IF EXISTS recipe_ingredients;
+DROP TABLE IF EXISTS recipes;
+DROP TABLE IF EXISTS jobs;
+DROP TABLE IF EXISTS chest_history;
+DROP TABLE IF EXISTS stock_snapshots;
+DROP TABLE IF EXISTS stock_alerts;
+DROP TABLE IF EXISTS bot_inventory;
+DROP TABLE IF EXISTS packed_shulkers;
 
 CREATE TABLE chests (
 	x FLOAT NOT NULL,
 	y FLOAT NOT NULL,
 	Z FLOAT NOT NULL,
+	-- NULL: takes any item without a dedicated barrel
+	-- 'item': only filter_value, 'tag': only items tagged filter_value
+	-- 'overflow': takes anything, but only once every other barrel is full
+	filter_kind TEXT CHECK (filter_kind IN ('item', 'tag', 'overflow')),
+	filter_value TEXT,
 	UNIQUE (x, y, z)
 );
 
+CREATE TABLE item_tags (
+	tag TEXT NOT NULL,
+	item_id TEXT NOT NULL,
+	PRIMARY KEY (tag, item_id)
+);
+
 CREATE TABLE chest_items (
 	chest_item_id BIGINT GENERATED ALWAYS AS IDENTITY,
 	x FLOAT NOT NULL,
@@ -26,6 +47,205 @@ CREATE TABLE chest_items (
 	UNIQUE (x, y, z, location_in_chest)
 );
 
+CREATE TABLE recipes (
+	recipe_id TEXT NOT NULL,
+	-- block the recipe is made at, e.g. 'crafting_table'
+	station TEXT NOT NULL,
+	output_item TEXT NOT NULL,
+	output_count SMALLINT NOT NULL,
+	-- saved by a player rather than loaded from the vanilla data files,
+	-- the planner tries these first
+	is_pattern BOOLEAN NOT NULL DEFAULT FALSE,
+	PRIMARY KEY (recipe_id)
+);
+CREATE INDEX recipes_output_item ON recipes (output_item);
+
+CREATE TABLE recipe_ingredients (
+	recipe_id TEXT NOT NULL REFERENCES recipes (recipe_id) ON DELETE CASCADE,
+	-- index into the 3x3 crafting grid row by row,
+	-- or the input slot for other stations
+	slot SMALLINT NOT NULL,
+	-- item ids, or tags prefixed with '#', any of which can be used
+	choices TEXT[] NOT NULL,
+	PRIMARY KEY (recipe_id, slot)
+);
+
+CREATE TABLE jobs (
+	job_id BIGINT GENERATED ALWAYS AS IDENTITY,
+	command TEXT NOT NULL,
+	-- player name, or 'ws:<address>' for websocket clients
+	requester TEXT NOT NULL,
+	-- 0: background, 1: normal, 2: urgent
+	priority SMALLINT NOT NULL DEFAULT 1,
+	status TEXT NOT NULL DEFAULT 'queued'
+		CHECK (status IN ('queued', 'running', 'done', 'failed', 'cancelled')),
+	attempts INT NOT NULL DEFAULT 0,
+	-- error message for failed jobs
+	result TEXT,
+	created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	started_at TIMESTAMPTZ,
+	finished_at TIMESTAMPTZ,
+	PRIMARY KEY (job_id)
+);
+CREATE INDEX jobs_unfinished ON jobs (job_id) WHERE status IN ('queued', 'running');
+
+CREATE INDEX chest_items_item_id ON chest_items (item_id);
+
+-- slots a running job is about to take from, so other jobs plan around them.
+-- A slot is reserved whole since whole stacks get moved
+CREATE TABLE reservations (
+	job_id BIGINT NOT NULL REFERENCES jobs (job_id) ON DELETE CASCADE,
+	x FLOAT NOT NULL,
+	y FLOAT NOT NULL,
+	z FLOAT NOT NULL,
+	location_in_chest INT NOT NULL,
+	item_id TEXT NOT NULL,
+	item_count SMALLINT NOT NULL,
+	PRIMARY KEY (x, y, z, location_in_chest)
+);
+CREATE INDEX reservations_job_id ON reservations (job_id);
+
+-- filled by a trigger on chest_items so every write path is covered. Rows are never
+-- updated, and only deleted by the retention pruning in `history_pruner`.
+-- No foreign key to chests, the history outlives the barrels it's about
+CREATE TABLE chest_history (
+	history_id BIGINT GENERATED ALWAYS AS IDENTITY,
+	x FLOAT NOT NULL,
+	y FLOAT NOT NULL,
+	z FLOAT NOT NULL,
+	location_in_chest INT NOT NULL,
+	-- NULL when the slot wasn't recorded before, or was removed
+	old_item_id TEXT,
+	old_item_count SMALLINT,
+	new_item_id TEXT,
+	new_item_count SMALLINT,
+	-- the command that made the change, like 'withdraw', or 'manual' outside the queue
+	cause TEXT NOT NULL,
+	job_id BIGINT,
+	requester TEXT,
+	changed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	PRIMARY KEY (history_id)
+);
+CREATE INDEX chest_history_old_item_id ON chest_history (old_item_id, changed_at);
+CREATE INDEX chest_history_new_item_id ON chest_history (new_item_id, changed_at);
+CREATE INDEX chest_history_requester ON chest_history (requester, changed_at);
+CREATE INDEX chest_history_changed_at ON chest_history (changed_at);
+
+-- per-item totals taken every `history.snapshot_interval_secs`, for trend queries
+CREATE TABLE stock_snapshots (
+	item_id TEXT NOT NULL,
+	total BIGINT NOT NULL,
+	taken_at TIMESTAMPTZ NOT NULL,
+	PRIMARY KEY (item_id, taken_at)
+);
+CREATE INDEX stock_snapshots_taken_at ON stock_snapshots (taken_at);
+
+CREATE TABLE stock_alerts (
+	item_id TEXT NOT NULL,
+	threshold BIGINT NOT NULL,
+	created_by TEXT NOT NULL,
+	-- set once the alert has gone out, cleared when the item is restocked
+	alerting BOOLEAN NOT NULL DEFAULT FALSE,
+	PRIMARY KEY (item_id)
+);
+
+-- what the bot is carrying, so items on their way between a barrel and a depot
+-- are still accounted for
+CREATE TABLE bot_inventory (
+	slot INT NOT NULL,
+	item_id TEXT NOT NULL,
+	item_count SMALLINT NOT NULL,
+	PRIMARY KEY (slot)
+);
+
+-- shulker boxes filled for a withdraw, so their contents can still be found
+-- after they've left storage
+-- depot is a depot name, 'shulker barrel' or 'packing station', records are
+-- dropped once a packed withdraw sees fewer boxes left there
+CREATE TABLE packed_shulkers (
+	packed_id BIGINT GENERATED ALWAYS AS IDENTITY,
+	item_id TEXT NOT NULL,
+	item_count INT NOT NULL,
+	depot TEXT NOT NULL,
+	job_id BIGINT,
+	requester TEXT,
+	packed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	PRIMARY KEY (packed_id)
+);
+CREATE INDEX packed_shulkers_item_id ON packed_shulkers (item_id, packed_at);
+
+-- the cause, job and requester come from settings the bot sets for its transaction
+CREATE OR REPLACE FUNCTION record_chest_history() RETURNS trigger AS $$
+	DECLARE
+		_slot chest_items;
+	BEGIN
+		IF TG_OP = 'DELETE' THEN
+			_slot := OLD;
+		ELSE
+			_slot := NEW;
+		END IF;
+		-- rewriting a slot with what it already held, or an empty slot, isn't a change
+		IF (TG_OP = 'UPDATE' AND OLD.item_id = NEW.item_id AND OLD.item_count = NEW.item_count)
+			OR (TG_OP = 'INSERT' AND NEW.item_count = 0)
+			OR (TG_OP = 'DELETE' AND OLD.item_count = 0) THEN
+			RETURN NULL;
+		END IF;
+		INSERT INTO chest_history (
+			x, y, z, location_in_chest,
+			old_item_id, old_item_count, new_item_id, new_item_count,
+			cause, job_id, requester
+		) VALUES (
+			_slot.x, _slot.y, _slot.z, _slot.location_in_chest,
+			CASE WHEN TG_OP <> 'INSERT' THEN OLD.item_id END,
+			CASE WHEN TG_OP <> 'INSERT' THEN OLD.item_count END,
+			CASE WHEN TG_OP <> 'DELETE' THEN NEW.item_id END,
+			CASE WHEN TG_OP <> 'DELETE' THEN NEW.item_count END,
+			COALESCE(NULLIF(current_setting('storage.cause', true), ''), 'manual'),
+			NULLIF(current_setting('storage.job_id', true), '')::BIGINT,
+			NULLIF(current_setting('storage.requester', true), '')
+		);
+		RETURN NULL;
+	END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER chest_items_history
+	AFTER INSERT OR UPDATE OR DELETE ON chest_items
+	FOR EACH ROW EXECUTE FUNCTION record_chest_history();
+
+-- wakes up the bot's alert watcher, postgres sends it once per transaction
+CREATE OR REPLACE FUNCTION notify_stock_changed() RETURNS trigger AS $$
+	BEGIN
+		PERFORM pg_notify('stock_changed', '');
+		RETURN NULL;
+	END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER chest_items_stock_changed
+	AFTER INSERT OR UPDATE OR DELETE ON chest_items
+	FOR EACH STATEMENT EXECUTE FUNCTION notify_stock_changed();
+
+CREATE OR REPLACE VIEW item_totals AS
+	SELECT chest_items.item_id, SUM(chest_items.item_count)::BIGINT AS total, COUNT(*) AS stacks,
+		COALESCE(SUM(chest_items.item_count) FILTER (WHERE reservations.job_id IS NOT NULL), 0)::BIGINT AS reserved
+		FROM chest_items
+		LEFT JOIN reservations ON reservations.x = chest_items.x
+			AND reservations.y = chest_items.y
+			AND reservations.z = chest_items.z
+			AND reservations.location_in_chest = chest_items.location_in_chest
+			AND reservations.item_id = chest_items.item_id
+		WHERE chest_items.item_count > 0
+		GROUP BY chest_items.item_id;
+
+CREATE OR REPLACE VIEW chest_fill AS
+	SELECT chests.x, chests.y, chests.z,
+		COUNT(chest_items.chest_item_id) AS slots,
+		COUNT(chest_items.chest_item_id) FILTER (WHERE chest_items.item_count > 0) AS used_slots
+		FROM chests
+		LEFT JOIN chest_items ON chest_items.x = chests.x
+			AND chest_items.y = chests.y
+			AND chest_items.z = chests.z
+		GROUP BY chests.x, chests.y, chests.z;
+
 DROP FUNCTION IF EXISTS insert_item_into_chest;
 CREATE OR REPLACE PROCEDURE insert_item_into_chest (
 	_x float,
@@ -82,7 +302,7 @@ CREATE OR REPLACE FUNCTION find_item(
 	location_in_chest INT
 ) as $$
 	BEGIN
-		RETURN QUERY SELECT chests.*, chest_items.item_count, chest_items.location_in_chest
+		RETURN QUERY SELECT chests.x, chests.y, chests.z, chest_items.item_count, chest_items.location_in_chest
 			FROM chests
 			JOIN chest_items ON chest_items.item_id = _item_id
 				AND chest_items.x = chests.x
@@ -90,3 +310,62 @@ CREATE OR REPLACE FUNCTION find_item(
 				AND chest_items.z = chests.z;
 	END;
 $$ LANGUAGE plpgsql;
+
+
+DROP FUNCTION IF EXISTS deposit_candidates;
+CREATE OR REPLACE FUNCTION deposit_candidates(
+	_item_id TEXT,
+	_air_id TEXT
+) RETURNS TABLE (
+	x FLOAT,
+	y FLOAT,
+	z FLOAT
+) AS $$
+	DECLARE
+		_has_dedicated BOOLEAN;
+	BEGIN
+		SELECT EXISTS (
+			SELECT 1 FROM chests
+			WHERE (chests.filter_kind = 'item' AND chests.filter_value = _item_id)
+				OR (chests.filter_kind = 'tag' AND EXISTS (
+					SELECT 1 FROM item_tags
+					WHERE item_tags.tag = chests.filter_value AND item_tags.item_id = _item_id
+				))
+		) INTO _has_dedicated;
+
+		RETURN QUERY SELECT chests.x, chests.y, chests.z
+			FROM chests
+			WHERE (
+				(chests.filter_kind = 'item' AND chests.filter_value = _item_id)
+				OR (chests.filter_kind = 'tag' AND EXISTS (
+					SELECT 1 FROM item_tags
+					WHERE item_tags.tag = chests.filter_value AND item_tags.item_id = _item_id
+				))
+				OR chests.filter_kind = 'overflow'
+				OR (chests.filter_kind IS NULL AND NOT _has_dedicated)
+			)
+			-- skip barrels that are known to be full of other items
+			AND EXISTS (
+				SELECT 1 FROM chest_items
+				WHERE chest_items.x = chests.x
+					AND chest_items.y = chests.y
+					AND chest_items.z = chests.z
+					AND chest_items.item_id IN (_item_id, _air_id)
+			)
+			ORDER BY
+				CASE chests.filter_kind
+					WHEN 'item' THEN 0
+					WHEN 'tag' THEN 1
+					WHEN 'overflow' THEN 3
+					ELSE 2
+				END,
+				-- top up partial stacks before starting new ones
+				EXISTS (
+					SELECT 1 FROM chest_items
+					WHERE chest_items.x = chests.x
+						AND chest_items.y = chests.y
+						AND chest_items.z = chests.z
+						AND chest_items.item_id = _item_id
+				) DESC;
+	END;
+$$ LANGUAGE plpgsql;
\ No newline at end of file
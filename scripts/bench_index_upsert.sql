@@ -0,0 +1,40 @@
+-- Compares writing barrel contents one slot at a time with `insert_item_into_chest`
+-- against the single UNNEST upsert per barrel that `save_chest_contents` does.
+-- Run against a scratch database that postgres_setup.sql was loaded into:
+--   psql -d chest_storage_bench -v barrels=200 -f scripts/bench_index_upsert.sql
+-- Both passes overwrite existing rows like a re-index does, so the history trigger
+-- fires for every slot in both.
+
+\set QUIET on
+\if :{?barrels}
+\else
+\set barrels 200
+\endif
+
+INSERT INTO chests (x, y, z) SELECT barrel, 0, 0 FROM generate_series(1, :barrels) barrel;
+INSERT INTO chest_items (x, y, z, location_in_chest, item_id, item_count, item_nbt)
+	SELECT barrel, 0, 0, slot, 'minecraft:air', 0, '\x0a000000'::bytea
+	FROM generate_series(1, :barrels) barrel, generate_series(0, 26) slot;
+
+-- one round trip and one transaction per slot
+SELECT clock_timestamp() AS started \gset
+SELECT format('CALL insert_item_into_chest(%s, 0, 0, %s, %L, 64::smallint, %L::bytea);',
+		barrel, slot, 'minecraft:dirt', '\x0a000000')
+	FROM generate_series(1, :barrels) barrel, generate_series(0, 26) slot \gexec
+SELECT round(extract(epoch FROM clock_timestamp() - :'started') * 1000) AS per_slot_ms \gset
+
+-- one round trip and one transaction per barrel
+SELECT clock_timestamp() AS started \gset
+SELECT format('INSERT INTO chest_items (x, y, z, location_in_chest, item_id, item_count, item_nbt) SELECT %s, 0, 0, slot.location_in_chest, slot.item_id, slot.item_count, %L::bytea FROM UNNEST(%L::int[], %L::text[], %L::smallint[]) AS slot(location_in_chest, item_id, item_count) ON CONFLICT (x, y, z, location_in_chest) DO UPDATE SET item_id = excluded.item_id, item_count = excluded.item_count, item_nbt = excluded.item_nbt;',
+		barrel, '\x0a000000',
+		(SELECT array_agg(slot) FROM generate_series(0, 26) slot),
+		(SELECT array_agg('minecraft:stone'::text) FROM generate_series(0, 26)),
+		(SELECT array_agg(64) FROM generate_series(0, 26)))
+	FROM generate_series(1, :barrels) barrel \gexec
+SELECT round(extract(epoch FROM clock_timestamp() - :'started') * 1000) AS per_barrel_ms \gset
+
+\echo :barrels 'barrels, per slot:' :per_slot_ms 'ms, per barrel:' :per_barrel_ms 'ms'
+
+DELETE FROM chest_history;
+DELETE FROM chest_items;
+DELETE FROM chests;
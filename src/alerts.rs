@@ -0,0 +1,170 @@
+use std::{collections::HashSet, net::SocketAddr, sync::Arc, time::Duration};
+
+use parking_lot::Mutex;
+use sqlx::{postgres::PgListener, PgPool, Row};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    config::CONFIG,
+    postgres::{
+        all_stock_alerts, delete_stock_alert, set_stock_alert, stock_alert_creator,
+        update_stock_alerts,
+    },
+    PeerMap,
+};
+
+/// How long a burst of storage writes, like an index, gets to settle before checking
+const SETTLE_MILLIS: u64 = 1000;
+/// How long to wait before listening again after losing the connection
+const RETRY_SECS: u64 = 10;
+
+/// Websocket clients that asked for low-stock alerts
+#[derive(Default, Clone)]
+pub struct AlertSubscribers(Arc<Mutex<HashSet<SocketAddr>>>);
+
+impl AlertSubscribers {
+    pub fn subscribe(&self, addr: SocketAddr) {
+        self.0.lock().insert(addr);
+    }
+
+    pub fn unsubscribe(&self, addr: SocketAddr) {
+        self.0.lock().remove(&addr);
+    }
+}
+
+/// Compares every threshold with the current totals and sends alerts for the ones that
+/// changed state. An alert only clears once the item is back above its threshold by
+/// `alerts.hysteresis_percent`, so stock hovering around it doesn't spam
+pub async fn check_alerts(
+    pool: &PgPool,
+    bot: &azalea::Client,
+    peers: &PeerMap,
+    subscribers: &AlertSubscribers,
+) -> Result<(), sqlx::Error> {
+    for row in update_stock_alerts(pool, CONFIG.alerts.hysteresis_percent).await? {
+        let item_id = row.get::<String, _>("item_id");
+        let total = row.get::<i64, _>("total");
+        let threshold = row.get::<i64, _>("threshold");
+        let message = if row.get::<bool, _>("alerting") {
+            format!(
+                "Low stock: {}x {} left, below {}",
+                total, item_id, threshold
+            )
+        } else {
+            format!(
+                "Restocked: {}x {} (threshold {})",
+                total, item_id, threshold
+            )
+        };
+
+        {
+            let peers = peers.lock();
+            for addr in subscribers.0.lock().iter() {
+                if let Some(tx) = peers.get(addr) {
+                    tx.unbounded_send(Message::Text(message.clone())).ok();
+                }
+            }
+        }
+        for player in &CONFIG.alerts.players {
+            bot.chat(&format!("/msg {} {}", player, message));
+        }
+    }
+    Ok(())
+}
+
+/// Checks alerts whenever `chest_items` changes, which the db reports for every write
+/// whether it came from a job, the manual index or anywhere else
+pub async fn alert_watcher(
+    pool: PgPool,
+    bot: azalea::Client,
+    peers: PeerMap,
+    subscribers: AlertSubscribers,
+) {
+    loop {
+        if let Err(err) = watch_stock(&pool, &bot, &peers, &subscribers).await {
+            println!("Error: {}", err);
+        }
+        tokio::time::sleep(Duration::from_secs(RETRY_SECS)).await;
+    }
+}
+
+async fn watch_stock(
+    pool: &PgPool,
+    bot: &azalea::Client,
+    peers: &PeerMap,
+    subscribers: &AlertSubscribers,
+) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen("stock_changed").await?;
+    // anything that changed while nobody was listening
+    check_alerts(pool, bot, peers, subscribers).await?;
+    loop {
+        listener.recv().await?;
+        tokio::time::sleep(Duration::from_millis(SETTLE_MILLIS)).await;
+        while let Ok(notification) = tokio::time::timeout(Duration::ZERO, listener.recv()).await {
+            notification?;
+        }
+        check_alerts(pool, bot, peers, subscribers).await?;
+    }
+}
+
+/// Runs `alert set <item> <threshold>`, `alert remove <item>` and `alert list`
+pub async fn run_alert_command(
+    pool: &PgPool,
+    command_arr: &[&str],
+    requester: &str,
+) -> Result<Vec<String>, sqlx::Error> {
+    let line = match (command_arr.get(1).copied(), command_arr.get(2)) {
+        (Some("set"), Some(item_id)) => {
+            let Some(Ok(threshold)) = command_arr.get(3).map(|count| count.parse::<i64>()) else {
+                return Ok(vec!["Usage: alert set <item> <threshold>".to_string()]);
+            };
+            if threshold < 0 {
+                return Ok(vec!["The threshold can't be negative".to_string()]);
+            }
+            set_stock_alert(pool, item_id, threshold, requester).await?;
+            format!(
+                "Alerting when there are fewer than {} {}",
+                threshold, item_id
+            )
+        }
+        (Some("remove"), Some(item_id)) => match stock_alert_creator(pool, item_id).await? {
+            None => format!("No alert for {}", item_id),
+            // the bot owner can remove anyone's
+            Some(creator) if creator != requester && requester != CONFIG.bot_owner => {
+                format!("The alert for {} was set by {}", item_id, creator)
+            }
+            Some(_) => {
+                delete_stock_alert(pool, item_id).await?;
+                format!("Removed the alert for {}", item_id)
+            }
+        },
+        (Some("list"), _) => {
+            let alerts = all_stock_alerts(pool).await?;
+            if alerts.is_empty() {
+                return Ok(vec!["No alerts set".to_string()]);
+            }
+            return Ok(alerts
+                .iter()
+                .map(|row| {
+                    format!(
+                        "{} below {}{}, set by {}",
+                        row.get::<&str, _>("item_id"),
+                        row.get::<i64, _>("threshold"),
+                        if row.get::<bool, _>("alerting") {
+                            " (low now)"
+                        } else {
+                            ""
+                        },
+                        row.get::<&str, _>("created_by")
+                    )
+                })
+                .collect());
+        }
+        (Some("subscribe" | "unsubscribe"), _) => {
+            "Only websocket clients can subscribe, players get whispers from the config".to_string()
+        }
+        _ => "Usage: alert <set|remove|list|subscribe|unsubscribe> [item] [threshold]".to_string(),
+    };
+    Ok(vec![line])
+}
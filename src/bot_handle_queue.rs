@@ -1,4 +1,9 @@
-use std::time::Duration;
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
 
 use azalea::container::ContainerHandle;
 use azalea::entity::Position;
@@ -9,345 +14,897 @@ use azalea::{
 };
 use azalea_core::BlockPos;
 use azalea_inventory::operations::QuickMoveClick;
-use azalea_inventory::ItemSlot;
+use azalea_inventory::{ItemSlot, ItemSlotData};
 use sqlx::{PgPool, Row};
+use tokio_tungstenite::tungstenite::Message;
 
 use crate::{
-    config::{Config, Depot, Region, CONFIG},
+    compact::{plan_compaction, run_compaction},
+    config::{Config, Depot, DepotKind, Exporter, Region, CONFIG},
+    crafting::{craft_into_inventory, run_craft_plan, CraftPlan, Planner},
+    error::BotError,
     find_blocks::find_blocks,
-    minecraft_handle::WebsocketQueue,
-    postgres::{create_chest, find_item, items_in_chest, set_item_in_chest},
+    jobs::{
+        attribute_to, finish_job, format_duration, start_job, Audit, Cancelled, Job, JobQueue,
+        JobStatus,
+    },
+    packing::{is_shulker_box, pack_withdraw, should_pack, unpack_depot},
+    postgres::{
+        all_patterns, clear_storage, delete_pattern, deposit_candidates, find_item, find_packed,
+        items_in_chest, replace_vanilla_data, reserve_item, save_bot_inventory,
+        save_chest_contents, save_pattern, set_chest_filter,
+    },
+    recipes::{parse_pattern, read_data_dir, RecipeBook, PATTERN_PREFIX},
+    PeerMap,
 };
 
-pub async fn bot_handle_queue(
-    queue: WebsocketQueue,
-    bot: azalea::Client,
-    pool: PgPool,
-) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn bot_handle_queue(queue: JobQueue, bot: azalea::Client, pool: PgPool, peers: PeerMap) {
     loop {
         let queue2 = queue.clone();
         let mut bot2 = bot.clone();
         let pool2 = pool.clone();
-        match tokio::spawn(
-            async move { bot_handle_queue0(queue2, &mut bot2, pool2).await.unwrap() },
-        )
-        .await
+        let peers2 = peers.clone();
+        // failed commands don't end the worker, so this only restarts it after a panic
+        if let Err(err) =
+            tokio::spawn(async move { bot_handle_queue0(queue2, &mut bot2, pool2, peers2).await })
+                .await
         {
-            Err(err) => {
+            println!("Error: {}", err);
+            let job = queue.current.lock().take();
+            let Some(job) = job else {
                 bot.chat("The queue thread died, check logs");
+                continue;
+            };
+            report(
+                &bot,
+                &peers,
+                &job,
+                &format!(
+                    "Job #{} ({}) failed: the queue thread died",
+                    job.job_id, job.command
+                ),
+            );
+            queue.job_finished(job.job_id);
+            if let Err(err) = finish_job(
+                &pool,
+                job.job_id,
+                JobStatus::Failed,
+                Some("the queue thread died"),
+            )
+            .await
+            {
                 println!("Error: {}", err);
             }
-            Ok(()) => unreachable!(),
-        };
+        }
     }
 }
+
 pub async fn bot_handle_queue0(
-    queue: WebsocketQueue,
+    queue: JobQueue,
     bot: &mut azalea::Client,
     pool: PgPool,
-) -> Result<(), Box<dyn std::error::Error>> {
+    peers: PeerMap,
+) {
     let config: Config = CONFIG.clone();
-    let region: Region = config.region;
-    let depot: Depot = config.depot;
+    let region: Region = config.region.clone();
+    // exporters that have already been reported as out of stock
+    let mut out_of_stock: HashSet<String> = HashSet::new();
+    let worker = Worker {
+        pool: &pool,
+        config: &config,
+        region: &region,
+        queue: &queue,
+        peers: &peers,
+    };
 
-    'queue: loop {
-        let command = queue.queue.lock().pop_front();
-        let command = match command {
-            Some(command) => command,
-            None => {
-                tokio::time::sleep(Duration::from_millis(100)).await;
-                continue;
-            }
+    loop {
+        let job = queue.pop().await;
+        if let Err(err) = run_job(job, bot, worker, &mut out_of_stock).await {
+            // the job's status couldn't be saved, it gets looked at again on restart
+            println!("Error: {}", err);
+        }
+    }
+}
+
+/// What the queue thread hands to every job it runs
+#[derive(Clone, Copy)]
+struct Worker<'a> {
+    pool: &'a PgPool,
+    config: &'a Config,
+    region: &'a Region,
+    queue: &'a JobQueue,
+    peers: &'a PeerMap,
+}
+
+/// Runs a job, records how it went and tells the requester if it didn't work out.
+/// Boxed since jobs can run other jobs through `run_preempting`
+fn run_job<'a>(
+    job: Job,
+    bot: &'a mut azalea::Client,
+    worker: Worker<'a>,
+    out_of_stock: &'a mut HashSet<String>,
+) -> Pin<Box<dyn Future<Output = Result<(), sqlx::Error>> + Send + 'a>> {
+    Box::pin(async move {
+        let Worker { pool, queue, .. } = worker;
+        // a preempted job goes back to being the current one afterwards
+        let _restore = RestoreCurrent {
+            queue,
+            job_id: job.job_id,
+            preempted: queue.current.lock().replace(job.clone()),
         };
-        let command = command.clone();
-        let command_arr = command.split(' ').collect::<Vec<_>>();
+        start_job(pool, job.job_id).await?;
 
-        println!("Recieved command: {}", command);
+        println!("Recieved command: {}", job.command);
 
-        match command_arr[0] {
-            "sayhi" => {
-                bot.chat("hi");
+        let result = attribute_to(job.clone(), run_command(&job, bot, worker, out_of_stock)).await;
+        // failed jobs can leave things in the inventory too, and the next job needs the room
+        match attribute_to(job.clone(), empty_inventory(bot, worker)).await {
+            Ok(leftover) if !leftover.is_empty() => report(
+                bot,
+                worker.peers,
+                &job,
+                &format!(
+                    "No room anywhere for {}, still holding it",
+                    leftover.join(", ")
+                ),
+            ),
+            Ok(_) => {}
+            Err(err) => println!(
+                "Error emptying inventory after job #{}: {}",
+                job.job_id, err
+            ),
+        }
+        let finished = match result {
+            Ok(()) => finish_job(pool, job.job_id, JobStatus::Done, None).await,
+            Err(BotError::Cancelled(cancelled)) => {
+                let progress = cancelled.to_string();
+                report(
+                    bot,
+                    worker.peers,
+                    &job,
+                    &format!("Job #{} {}", job.job_id, progress),
+                );
+                finish_job(pool, job.job_id, JobStatus::Cancelled, Some(&progress)).await
             }
-            "index" => {
-                bot.chat("Indexing...");
-
-                let storage_blocks = find_blocks(
-                    bot.world().read(),
-                    bot.position(),
-                    &azalea::Block::Barrel.into(),
+            Err(err) => {
+                println!("Job #{} failed: {:?}", job.job_id, err);
+                report(
+                    bot,
+                    worker.peers,
+                    &job,
+                    &format!("Job #{} ({}) failed: {}", job.job_id, job.command, err),
                 );
-                let storage_blocks = storage_blocks
-                    .iter()
-                    .filter(|block| {
-                        block.y >= region.min_y
-                            && block.y <= region.max_y
-                            && block.x >= region.x1
-                            && block.x <= region.x2
-                            && block.z >= region.z1
-                            && block.z <= region.z2
-                    })
-                    .map(|block| block.clone())
-                    .collect::<Vec<_>>();
-
-                // for block in storage_blocks {
-                //     bot.chat(&format!("Storage block: {:?}", block.to_vec3_floored()));
-                // }
-                bot.chat(&format!("Indexing {} storage blocks", storage_blocks.len()));
-                'blocks: for block in storage_blocks {
-                    bot.write_packet(ServerboundGamePacket::MovePlayerPos(
-                        ServerboundMovePlayerPosPacket {
-                            x: block.x as f64 + 0.5,
-                            y: region.walking_level as f64,
-                            z: block.z as f64 + 0.5,
-                            on_ground: true,
-                        },
-                    ));
-                    {
-                        let mut ecs = bot.ecs.lock();
-                        let mut entity_mut = ecs.entity_mut(bot.entity);
-                        let mut position = entity_mut.get_mut::<Position>().unwrap();
-                        position.x = block.x as f64 + 0.5;
-                        position.z = block.z as f64 + 0.5;
-                    }
+                finish_job(pool, job.job_id, JobStatus::Failed, Some(&err.to_string())).await
+            }
+        };
+        // the job itself is over either way, resuming it after a restart would be worse
+        if let Err(err) = finished {
+            println!("Error recording the end of job #{}: {}", job.job_id, err);
+        }
+        Ok(())
+    })
+}
 
-                    let mut barrel = bot.open_container(block).await;
-                    let mut retries = 5;
-                    while barrel.is_none() && retries > 0 {
-                        bot.chat("retrying");
-                        retries -= 1;
-                        barrel = bot.open_container(block).await;
-                        match barrel {
-                            Some(_) => {
-                                bot.chat("retry successful");
-                            }
-                            None => {
-                                bot.chat(&format!(
-                                    "retry failed, {} attempt(s) remaining",
-                                    retries
-                                ));
-                            }
-                        }
-                    }
-                    let barrel = match barrel {
-                        Some(barrel) => barrel,
-                        None => {
-                            println!("failed to open storage block at [{:?}] for an unknown reason (this is probably my fault)", block);
-                            continue 'blocks;
-                        }
-                    };
+/// Puts the preempted job back as the current one however `run_job` exits,
+/// so a failed db write can't leave a finished job looking like it's running.
+/// A panic leaves the job that panicked as the current one instead, since that's
+/// how `bot_handle_queue` knows which job to fail
+struct RestoreCurrent<'a> {
+    queue: &'a JobQueue,
+    job_id: i64,
+    preempted: Option<Job>,
+}
+
+impl Drop for RestoreCurrent<'_> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        self.queue.job_finished(self.job_id);
+        *self.queue.current.lock() = self.preempted.take();
+    }
+}
 
-                    create_chest(&pool, block.x as f64, block.y as f64, block.z as f64).await?;
+/// Sends a message to whoever queued the job, websocket clients get it on their
+/// connection and everyone else in chat
+fn report(bot: &azalea::Client, peers: &PeerMap, job: &Job, message: &str) {
+    if let Some(addr) = job
+        .requester
+        .strip_prefix("ws:")
+        .and_then(|addr| addr.parse::<SocketAddr>().ok())
+    {
+        if let Some(tx) = peers.lock().get(&addr) {
+            tx.unbounded_send(Message::Text(message.to_string())).ok();
+            return;
+        }
+    }
+    match job.requester.as_str() {
+        // background jobs have nobody to tell
+        "importer" | "exporter" => bot.chat(message),
+        requester => bot.chat(&format!("{}: {}", requester, message)),
+    }
+}
+
+/// Runs every waiting job that outranks the running one. Long jobs call this between
+/// barrels so a withdraw doesn't have to wait for a whole index
+async fn run_preempting(
+    running: &Job,
+    bot: &mut azalea::Client,
+    worker: Worker<'_>,
+    out_of_stock: &mut HashSet<String>,
+) -> Result<(), sqlx::Error> {
+    // a paused queue doesn't start anything, preempting jobs included
+    while !worker.queue.is_paused() {
+        let Some(job) = worker.queue.try_pop_above(running.priority) else {
+            break;
+        };
+        println!("Job #{} preempts job #{}", job.job_id, running.job_id);
+        run_job(job, bot, worker, out_of_stock).await?;
+    }
+    Ok(())
+}
+
+async fn run_command(
+    job: &Job,
+    bot: &mut azalea::Client,
+    worker: Worker<'_>,
+    out_of_stock: &mut HashSet<String>,
+) -> Result<(), BotError> {
+    let Worker {
+        pool,
+        config,
+        region,
+        queue,
+        ..
+    } = worker;
+    let command_arr = job.command.split(' ').collect::<Vec<_>>();
+
+    match command_arr[0] {
+        "sayhi" => {
+            bot.chat("hi");
+        }
+        "index" => {
+            bot.chat("Indexing...");
 
-                    println!("Getting contents");
-                    for (index, slot) in match barrel.contents() {
-                        Some(contents) => contents,
+            let storage_blocks = find_blocks(
+                bot.world().read(),
+                bot.position(),
+                &azalea::Block::Barrel.into(),
+            );
+            let storage_blocks = storage_blocks
+                .iter()
+                .filter(|block| {
+                    block.y >= region.min_y
+                        && block.y <= region.max_y
+                        && block.x >= region.x1
+                        && block.x <= region.x2
+                        && block.z >= region.z1
+                        && block.z <= region.z2
+                })
+                .map(|block| block.clone())
+                .collect::<Vec<_>>();
+
+            // for block in storage_blocks {
+            //     bot.chat(&format!("Storage block: {:?}", block.to_vec3_floored()));
+            // }
+            let total = storage_blocks.len();
+            let started = Instant::now();
+            bot.chat(&format!("Indexing {} storage blocks", total));
+            'blocks: for (indexed, block) in storage_blocks.into_iter().enumerate() {
+                queue.set_progress(job, indexed, total, "barrel");
+                queue
+                    .checkpoint(
+                        job,
+                        &format!("indexed {} of {} storage blocks", indexed, total),
+                    )
+                    .await?;
+                teleport_to(bot, block.x, region.walking_level, block.z)?;
+
+                let mut barrel = bot.open_container(block).await;
+                let mut retries = 5;
+                while barrel.is_none() && retries > 0 {
+                    bot.chat("retrying");
+                    retries -= 1;
+                    barrel = bot.open_container(block).await;
+                    match barrel {
+                        Some(_) => {
+                            bot.chat("retry successful");
+                        }
                         None => {
-                            bot.chat(&format!(
-                                "skipping storage block at [{:?}] because of an error (mta pls fix)",
-                                block
-                            ));
-                            continue 'blocks;
+                            bot.chat(&format!("retry failed, {} attempt(s) remaining", retries));
                         }
                     }
-                    .iter()
-                    .enumerate()
-                    {
-                        set_item_in_chest(
-                            &pool,
-                            block.x as f64,
-                            block.y as f64,
-                            block.z as f64,
-                            index.try_into().unwrap_or(-1),
-                            &slot.kind().to_string(),
-                            slot.count() as i16,
-                            None,
-                        )
-                        .await
-                        .unwrap();
-
-                        println!("Checking slot {index}: {slot:?}");
-                        if let ItemSlot::Present(item) = slot {
-                            bot.chat(&format!("found item: [{} x{}]", item.kind, item.count));
-
-                            if item.kind == azalea::Item::Diamond
-                                || item.kind == azalea::Item::DiamondBlock
-                            {
-                                println!("clicking slot ^");
-                                barrel.click(QuickMoveClick::Left { slot: index as u16 });
-                            }
+                }
+                let barrel = match barrel {
+                    Some(barrel) => barrel,
+                    None => {
+                        println!("failed to open storage block at [{:?}] for an unknown reason (this is probably my fault)", block);
+                        continue 'blocks;
+                    }
+                };
+
+                println!("Getting contents");
+                let contents = match barrel.contents() {
+                    Some(contents) => contents,
+                    None => {
+                        bot.chat(&format!(
+                            "skipping storage block at [{:?}] because of an error (mta pls fix)",
+                            block
+                        ));
+                        continue 'blocks;
+                    }
+                };
+                save_contents(pool, block, &contents).await?;
+                for (index, slot) in contents.iter().enumerate() {
+                    println!("Checking slot {index}: {slot:?}");
+                    if let ItemSlot::Present(item) = slot {
+                        bot.chat(&format!("found item: [{} x{}]", item.kind, item.count));
+
+                        if item.kind == azalea::Item::Diamond
+                            || item.kind == azalea::Item::DiamondBlock
+                        {
+                            println!("clicking slot ^");
+                            barrel.click(QuickMoveClick::Left { slot: index as u16 });
                         }
                     }
+                }
+
+                drop(barrel);
+                bot.run_schedule_sender
+                    .send(())
+                    .map_err(|_| BotError::Disconnected)?;
+                // tokio::time::sleep(Duration::from_millis(100)).await;
 
-                    drop(barrel);
-                    bot.run_schedule_sender.send(())?;
-                    // tokio::time::sleep(Duration::from_millis(100)).await;
+                run_preempting(job, bot, worker, out_of_stock).await?;
+            }
+
+            bot.chat(&format!(
+                "Done! Indexed {} storage blocks in {:.1}s",
+                total,
+                started.elapsed().as_secs_f32()
+            ));
+        }
+        "cleardb" => {
+            clear_storage(pool, &Audit::current()).await?;
+            bot.chat("Cleared DB");
+        }
+        "viewchest" => {
+            let (x, y, z) = parse_pos(command_arr, "Usage: viewchest <x> <y> <z>")?;
+            let res = items_in_chest(pool, x, y, z).await?;
+            if res.len() == 0 {
+                bot.chat("No items found at location");
+            }
+            let mut non_air_items = 0;
+            for item in res {
+                let item_id: &str = item.get("item_id");
+                if item_id != "minecraft:air" {
+                    non_air_items += 1;
+                    bot.chat(&format!(
+                        "{} x{}",
+                        item_id,
+                        item.get::<i16, _>("item_count")
+                    ));
+                }
+            }
+            if non_air_items == 0 {
+                bot.chat("No items in storage block");
+            }
+        }
+        "find" => {
+            let item_id = command_arr
+                .get(1)
+                .copied()
+                .ok_or_else(|| BotError::Parse("Usage: find <item>".to_string()))?;
+            let res = find_item(pool, item_id).await?;
+            for location in res {
+                let item_count = location.get::<i16, _>("item_count");
+                let x = location.get::<f64, _>("x");
+                let y = location.get::<f64, _>("y");
+                let z = location.get::<f64, _>("z");
+                bot.chat(&format!(
+                    "Found {}x of {} at ({}, {}, {})",
+                    item_count, item_id, x, y, z
+                ));
+            }
+            for packed in find_packed(pool, item_id, 5).await? {
+                bot.chat(&format!(
+                    "Packed {}x of {} in a shulker box left at {}, {} ago",
+                    packed.get::<i32, _>("item_count"),
+                    item_id,
+                    packed.get::<&str, _>("depot"),
+                    format_duration(Duration::from_secs(
+                        packed.get::<i64, _>("age_secs").max(0) as u64
+                    ))
+                ));
+            }
+        }
+        "withdraw" => {
+            let withdraw_item_id = match command_arr.get(1) {
+                Some(id) => *id,
+                None => "0",
+            };
+            // the rest of the arguments can be a count, a depot, `craft` and/or `packed`
+            let mut count: Option<i32> = None;
+            let mut depot_name: Option<&str> = None;
+            let mut craft_shortfall = false;
+            let mut packed = false;
+            for arg in command_arr.iter().skip(2) {
+                match (*arg, arg.parse::<i32>()) {
+                    (_, Ok(parsed)) => count = Some(parsed),
+                    ("craft", _) => craft_shortfall = true,
+                    ("packed", _) => packed = true,
+                    (name, _) => depot_name = Some(name),
+                }
+            }
+            if count.is_some_and(|count| count < 1) {
+                return Err(BotError::Parse(
+                    "usage: withdraw <item> [count] [depot] [craft|packed], count at least 1"
+                        .to_string(),
+                ));
+            }
+            // crafting plans for an exact amount
+            if craft_shortfall && count.is_none() {
+                return Err(BotError::Parse(
+                    "usage: withdraw <item> <count> [depot] craft".to_string(),
+                ));
+            }
+            if packed && craft_shortfall {
+                return Err(BotError::Parse(
+                    "packed and craft can't be combined, crafted items aren't packed".to_string(),
+                ));
+            }
+            // packing takes one box at a time until the count is reached
+            if packed && count.is_none() {
+                return Err(BotError::Parse(
+                    "usage: withdraw <item> <count> [depot] packed".to_string(),
+                ));
+            }
+            if let Some(depot) = depot_name.and_then(|name| config.depot_by_name(name)) {
+                if depot.kind == DepotKind::Input {
+                    return Err(BotError::Permission(format!(
+                        "depot '{}' is an input depot, refusing to withdraw into it",
+                        depot.name
+                    )));
+                }
+            }
+            let depot: Depot = match config.depot(DepotKind::Output, depot_name) {
+                Some(depot) => depot.clone(),
+                None => {
+                    bot.chat("No output depot found");
+                    return Ok(());
                 }
+            };
 
-                bot.chat("Done!");
+            let packing = config.packing.as_ref();
+            if packed && packing.is_none() {
+                return Err(BotError::NoStation("packing station".to_string()));
             }
-            "cleardb" => {
-                sqlx::query("DELETE FROM chest_items")
-                    .fetch_optional(&pool)
+            let mut packed_count = 0;
+            if let (Some(count), false, Some(packing)) = (count, craft_shortfall, packing) {
+                if packed || should_pack(packing, withdraw_item_id, count) {
+                    // other shared depots take the boxes that don't fit
+                    let depots = std::iter::once(&depot)
+                        .chain(config.depots.iter().filter(|other| {
+                            other.kind == DepotKind::Output
+                                && other.owner.is_none()
+                                && other.name != depot.name
+                        }))
+                        .collect::<Vec<_>>();
+                    packed_count = pack_withdraw(
+                        bot,
+                        pool,
+                        region,
+                        packing,
+                        &depots,
+                        withdraw_item_id,
+                        count,
+                        || queue.is_cancelled(job.job_id),
+                    )
                     .await?;
-                sqlx::query("DELETE FROM chests")
-                    .fetch_optional(&pool)
-                    .await?;
-                bot.chat("Cleared DB");
-            }
-            "viewchest" => {
-                let x = command_arr[1].parse::<f64>()?;
-                let y = command_arr[2].parse::<f64>()?;
-                let z = command_arr[3].parse::<f64>()?;
-                let res = items_in_chest(&pool, x, y, z).await?;
-                if res.len() == 0 {
-                    bot.chat("No items found at location");
+                    if packed_count > 0 {
+                        bot.chat(&format!(
+                            "Withdrew {}x {} packed in shulker boxes",
+                            packed_count, withdraw_item_id
+                        ));
+                    }
+                    // only an explicit `packed` fails, big withdraws fall back to loose trips
+                    if packed && packed_count < count && !queue.is_cancelled(job.job_id) {
+                        return Err(BotError::Packing(format!(
+                            "only {}/{} {} got packed",
+                            packed_count, count, withdraw_item_id
+                        )));
+                    }
                 }
-                let mut non_air_items = 0;
-                for item in res {
-                    let item_id: &str = item.get("item_id");
-                    if item_id != "minecraft:air" {
-                        non_air_items += 1;
+            }
+            match (count, craft_shortfall) {
+                (Some(count), true) => {
+                    let book = RecipeBook::load(pool).await?;
+                    let stock = Planner::load_stock(pool).await?;
+                    let plan = Planner::new(&book, stock).plan(withdraw_item_id, count);
+                    if !plan.missing.is_empty() {
                         bot.chat(&format!(
-                            "{} x{}",
-                            item_id,
-                            item.get::<i16, _>("item_count")
+                            "Can't get {}x {}, missing {}",
+                            count,
+                            withdraw_item_id,
+                            CraftPlan::describe(&plan.missing)
                         ));
+                        return Ok(());
+                    }
+                    bot.chat(&format!("Plan: {}", plan.summary()));
+                    craft_into_inventory(bot, pool, region, &plan).await?;
+                    if deliver(bot, pool, &depot, withdraw_item_id).await? {
+                        bot.chat(&format!("Depot {} is full", depot.name));
                     }
                 }
-                if non_air_items == 0 {
-                    bot.chat("No items in storage block");
+                (Some(count), _) if packed_count >= count => {}
+                (count, _) => {
+                    // the inventory only holds so much, so big withdraws take several trips
+                    let mut withdrawn = 0;
+                    loop {
+                        let remaining = count.map(|count| count - packed_count - withdrawn);
+                        if remaining.is_some_and(|remaining| remaining <= 0)
+                            || queue.is_cancelled(job.job_id)
+                        {
+                            break;
+                        }
+                        let taken = take_from_storage(
+                            bot,
+                            pool,
+                            region,
+                            withdraw_item_id,
+                            remaining,
+                            || queue.is_cancelled(job.job_id),
+                        )
+                        .await?;
+                        if taken == 0 {
+                            break;
+                        }
+                        withdrawn += taken;
+                        if deliver(bot, pool, &depot, withdraw_item_id).await? {
+                            bot.chat(&format!("Depot {} is full", depot.name));
+                            break;
+                        }
+                        if let Some(count) = count {
+                            queue.set_progress(
+                                job,
+                                (packed_count + withdrawn).min(count) as usize,
+                                count as usize,
+                                "item",
+                            );
+                        }
+                    }
+                    bot.chat(&format!("Withdrew {}x {}", withdrawn, withdraw_item_id));
+                }
+            }
+
+            if queue.is_cancelled(job.job_id) {
+                return Err(Cancelled("delivered what was withdrawn so far".to_string()).into());
+            }
+        }
+        "export" => {
+            let exporter: Exporter = match command_arr.get(1).and_then(|name| config.exporter(name))
+            {
+                Some(exporter) => exporter.clone(),
+                None => {
+                    println!("export: no exporter named {:?}", command_arr.get(1));
+                    return Ok(());
                 }
+            };
+
+            teleport_to(bot, exporter.x, exporter.y, exporter.z)?;
+            let stocked = count_in_container(bot, exporter.container_pos(), &exporter.item).await?;
+            if stocked >= exporter.count {
+                out_of_stock.remove(&exporter.name);
+                return Ok(());
             }
-            "find" => {
-                let item_id = command_arr[1];
-                let res = find_item(&pool, item_id).await?;
-                for location in res {
-                    let item_count = location.get::<i16, _>("item_count");
-                    let x = location.get::<f64, _>("x");
-                    let y = location.get::<f64, _>("y");
-                    let z = location.get::<f64, _>("z");
+
+            let wanted = exporter.count - stocked;
+            let taken =
+                take_from_storage(bot, pool, region, &exporter.item, Some(wanted), || false)
+                    .await?;
+            if taken == 0 {
+                // only report once until the exporter has been restocked
+                if out_of_stock.insert(exporter.name.clone()) {
                     bot.chat(&format!(
-                        "Found {}x of {} at ({}, {}, {})",
-                        item_count, item_id, x, y, z
+                        "Exporter '{}': storage is out of {}",
+                        exporter.name, exporter.item
                     ));
                 }
+                return Ok(());
             }
-            "withdraw" => {
-                let withdraw_item_id = match command_arr.get(1) {
-                    Some(id) => *id,
-                    None => "0",
-                };
+            out_of_stock.remove(&exporter.name);
 
-                let res = find_item(&pool, withdraw_item_id).await?;
-                for location in res {
-                    let item_count = location.get::<i16, _>("item_count");
-                    let x = location.get::<f64, _>("x");
-                    let y = location.get::<f64, _>("y");
-                    let z = location.get::<f64, _>("z");
+            teleport_to(bot, exporter.x, exporter.y, exporter.z)?;
+            put_into_container(bot, exporter.container_pos(), |item| {
+                item.kind.to_string() == exporter.item
+            })
+            .await?;
+            if taken < wanted {
+                bot.chat(&format!(
+                    "Exporter '{}': storage only had {}x {}, wanted {}",
+                    exporter.name, taken, exporter.item, wanted
+                ));
+            }
+        }
+        "deposit" => {
+            let depot_name = command_arr.get(1).copied();
+            if let Some(depot) = depot_name.and_then(|name| config.depot_by_name(name)) {
+                if depot.kind == DepotKind::Output {
+                    return Err(BotError::Permission(format!(
+                        "depot '{}' is an output depot, refusing to deposit from it",
+                        depot.name
+                    )));
+                }
+            }
+            let depot: Depot = match config.depot(DepotKind::Input, depot_name) {
+                Some(depot) => depot.clone(),
+                None => {
+                    bot.chat("No input depot found");
+                    return Ok(());
+                }
+            };
+
+            let unpacking = config
+                .packing
+                .as_ref()
+                .filter(|packing| packing.unpack_deposits);
+            // boxes stay in the depot until there's room to unpack them
+            take_from_depot(bot, &depot, |item| {
+                unpacking.is_none() || !is_shulker_box(&item.kind.to_string())
+            })
+            .await?;
+            let leftover = store_inventory(bot, pool, region).await?;
+            if !leftover.is_empty() {
+                bot.chat(&format!("No room in storage for {}", leftover.join(", ")));
+            }
+            if let Some(packing) = unpacking {
+                let unpacked = unpack_depot(bot, pool, region, packing, &depot, |_| true).await?;
+                if unpacked > 0 {
+                    bot.chat(&format!("Unpacked {} shulker box(es)", unpacked));
+                }
+            }
+        }
+        "import" => {
+            let depot: Depot = match command_arr
+                .get(1)
+                .and_then(|name| config.depot_by_name(name))
+            {
+                Some(depot) if depot.kind == DepotKind::Input => depot.clone(),
+                _ => {
+                    println!("import: no input depot named {:?}", command_arr.get(1));
+                    return Ok(());
+                }
+            };
+            let importer = match &depot.importer {
+                Some(importer) => importer.clone(),
+                None => {
+                    println!("import: depot '{}' has no importer configured", depot.name);
+                    return Ok(());
+                }
+            };
+
+            let unpacking = config
+                .packing
+                .as_ref()
+                .filter(|packing| packing.unpack_deposits);
+            let moved = take_from_depot(bot, &depot, |item| {
+                let item_id = item.kind.to_string();
+                importer.accepts(&item_id) && (unpacking.is_none() || !is_shulker_box(&item_id))
+            })
+            .await?;
+            if moved > 0 {
+                bot.chat(&format!(
+                    "Imported {} stack(s) from depot '{}'",
+                    moved, depot.name
+                ));
+                let leftover = store_inventory(bot, pool, region).await?;
+                if !leftover.is_empty() {
+                    bot.chat(&format!("No room in storage for {}", leftover.join(", ")));
+                }
+            }
+            if let Some(packing) = unpacking {
+                let unpacked = unpack_depot(bot, pool, region, packing, &depot, |item_id| {
+                    importer.accepts(item_id)
+                })
+                .await?;
+                if unpacked > 0 {
                     bot.chat(&format!(
-                        "Found {}x of {} at ({}, {}, {})",
-                        item_count, withdraw_item_id, x, y, z
+                        "Unpacked {} shulker box(es) from depot '{}'",
+                        unpacked, depot.name
                     ));
-                    teleport_to(&bot, x as i32, region.walking_level, z as i32);
-                    let blockpos = BlockPos {
-                        x: x as i32,
-                        y: y as i32,
-                        z: z as i32,
-                    };
-                    let barrel = match get_storage_handle(bot, blockpos).await {
-                        Some(barrel) => barrel,
-                        None => {
-                            println!("failed to open storage block at [{:?}] for an unknown reason (this is probably my fault)", blockpos);
-                            continue 'queue;
-                        }
-                    };
-                    let contents = match barrel.contents() {
-                        Some(contents) => contents,
-                        None => {
-                            println!("Failed to get contents of chest at [{:?}]", blockpos);
-                            continue 'queue;
-                        }
-                    };
-                    create_chest(&pool, x, y, z).await.unwrap();
-                    for (index, slot) in contents.iter().enumerate() {
-                        println!("Checking slot {index}: {slot:?}");
-                        if let ItemSlot::Present(item) = slot {
-                            bot.chat(&format!("found item: [{} x{}]", item.kind, item.count));
+                }
+            }
+        }
+        "compact" => {
+            let dry_run = command_arr.get(1) == Some(&"dry");
+            let mut plan = plan_compaction(pool).await?;
+            let freed_slots = plan.freed_slots().count();
+            if plan.moves.is_empty() {
+                bot.chat("Nothing to compact");
+                return Ok(());
+            }
+            bot.chat(&format!(
+                "Compaction plan: {} move(s) across {} item(s), frees {} slot(s)",
+                plan.moves.len(),
+                plan.groups.len(),
+                freed_slots
+            ));
+            if dry_run {
+                for compact_move in &plan.moves {
+                    bot.chat(&format!(
+                        "move {}x {} from {:?} slot {} to {:?} slot {}",
+                        compact_move.count,
+                        compact_move.item_id,
+                        compact_move.from.pos,
+                        compact_move.from.slot,
+                        compact_move.to.pos,
+                        compact_move.to.slot
+                    ));
+                }
+                for location in plan.freed_slots() {
+                    bot.chat(&format!("frees {:?} slot {}", location.pos, location.slot));
+                }
+                return Ok(());
+            }
 
-                            println!("clicking slot ^");
-                            barrel.click(QuickMoveClick::Left { slot: index as u16 });
-                        }
+            while !run_compaction(bot, pool, region, &plan, queue, job).await? {
+                queue
+                    .checkpoint(job, "stopped partway through compacting")
+                    .await?;
+                run_preempting(job, bot, worker, out_of_stock).await?;
+                // whatever ran in between may have moved things around
+                plan = plan_compaction(pool).await?;
+            }
+            bot.chat("Done compacting");
+        }
+        "loadrecipes" => {
+            let Some(path) = command_arr.get(1) else {
+                bot.chat("Usage: loadrecipes <path to data/minecraft>");
+                return Ok(());
+            };
+            let (recipes, tags) = read_data_dir(Path::new(path)).map_err(|err| {
+                BotError::Parse(format!("couldn't read recipes from {}: {}", path, err))
+            })?;
+            replace_vanilla_data(pool, &recipes, &tags).await?;
+            bot.chat(&format!(
+                "Loaded {} recipe(s) and {} item tag(s)",
+                recipes.len(),
+                tags.len()
+            ));
+        }
+        "craft" | "smelt" => {
+            let smelt = command_arr[0] == "smelt";
+            let (Some(item_id), Some(count)) = (
+                command_arr.get(1),
+                command_arr
+                    .get(2)
+                    .and_then(|count| count.parse::<i32>().ok())
+                    .filter(|count| *count >= 1),
+            ) else {
+                bot.chat(&format!("Usage: {} <item> <count>", command_arr[0]));
+                return Ok(());
+            };
+            let book = RecipeBook::load(pool).await?;
+            let stock = Planner::load_stock(pool).await?;
+            let planner = Planner::new(&book, stock);
+            let plan = if smelt {
+                planner.plan_smelt(item_id, count)
+            } else {
+                planner.plan_craft(item_id, count)
+            };
+            if !plan.missing.is_empty() {
+                bot.chat(&format!(
+                    "Can't {} {}x {}, missing {}",
+                    command_arr[0],
+                    count,
+                    item_id,
+                    CraftPlan::describe(&plan.missing)
+                ));
+                return Ok(());
+            }
 
-                        set_item_in_chest(
-                            &pool,
-                            x,
-                            y,
-                            z,
-                            index.try_into().unwrap_or(-1),
-                            &azalea::Item::Air.to_string(),
-                            0 as i16,
-                            None,
-                        )
-                        .await
-                        .unwrap();
-                    }
+            bot.chat(&format!(
+                "{} {}x {} in {} step(s) from {}",
+                if smelt { "Smelting" } else { "Crafting" },
+                count,
+                item_id,
+                plan.steps.len(),
+                CraftPlan::describe(&plan.withdraw)
+            ));
+            run_craft_plan(bot, pool, region, &plan).await?;
+            bot.chat(&format!(
+                "{} {}x {}",
+                if smelt { "Smelted" } else { "Crafted" },
+                count,
+                item_id
+            ));
+        }
+        "pattern" => match (command_arr.get(1).copied(), command_arr.get(2)) {
+            (Some("add"), Some(name)) => match parse_pattern(name, &command_arr[3..]) {
+                Ok(pattern) => {
+                    save_pattern(pool, &pattern).await?;
+                    bot.chat(&format!(
+                        "Saved pattern '{}' for {}x {} at the {}",
+                        name, pattern.output_count, pattern.output_item, pattern.station
+                    ));
                 }
-
-                teleport_to(&bot, depot.x, depot.y, depot.z);
-                let blockpos = BlockPos {
-                    x: depot.storage_x,
-                    y: depot.storage_y,
-                    z: depot.storage_z,
-                };
-                let barrel = match get_storage_handle(bot, blockpos).await {
-                    Some(barrel) => barrel,
-                    None => {
-                        println!("failed to open storage block at [{:?}] for an unknown reason (this is probably my fault)", blockpos);
-                        continue 'queue;
-                    }
-                };
-                let player_slots = barrel.menu().unwrap().player_slots_range();
-                for slot in player_slots {
-                    barrel.click(QuickMoveClick::Left { slot: slot as u16 });
+                Err(err) => bot.chat(&err),
+            },
+            (Some("remove"), Some(name)) => {
+                if delete_pattern(pool, &format!("{}{}", PATTERN_PREFIX, name)).await? {
+                    bot.chat(&format!("Removed pattern '{}'", name));
+                } else {
+                    bot.chat(&format!("No pattern named '{}'", name));
                 }
             }
-            "deposit" => {
-                teleport_to(&bot, depot.x, depot.y, depot.z);
-                let blockpos = BlockPos {
-                    x: depot.storage_x,
-                    y: depot.storage_y,
-                    z: depot.storage_z,
-                };
-                let barrel = match get_storage_handle(bot, blockpos).await {
-                    Some(barrel) => barrel,
-                    None => {
-                        println!("failed to open storage block at [{:?}] for an unknown reason (this is probably my fault)", blockpos);
-                        continue 'queue;
-                    }
-                };
-                let contents = match barrel.contents() {
-                    Some(contents) => contents,
-                    None => {
-                        println!("Failed to get contents of chest at [{:?}]", blockpos);
-                        continue 'queue;
-                    }
-                };
-                for (index, slot) in contents.iter().enumerate() {
-                    println!("Checking slot {index}: {slot:?}");
-                    if let ItemSlot::Present(item) = slot {
-                        bot.chat(&format!("found item: [{} x{}]", item.kind, item.count));
-                        barrel.click(QuickMoveClick::Left { slot: index as u16 });
-                    }
+            (Some("list"), _) => {
+                let patterns = all_patterns(pool).await?;
+                if patterns.is_empty() {
+                    bot.chat("No saved patterns");
+                }
+                for pattern in patterns {
+                    let recipe_id: &str = pattern.get("recipe_id");
+                    bot.chat(&format!(
+                        "{}: {}x {} at the {}",
+                        recipe_id.trim_start_matches(PATTERN_PREFIX),
+                        pattern.get::<i16, _>("output_count"),
+                        pattern.get::<&str, _>("output_item"),
+                        pattern.get::<&str, _>("station")
+                    ));
                 }
             }
-            _ => {
-                bot.chat("unknown command");
+            _ => bot.chat("Usage: pattern <add|remove|list> [name] [...]"),
+        },
+        "filter" => {
+            let (x, y, z) = parse_pos(
+                command_arr,
+                "Usage: filter <x> <y> <z> <item <id> | tag <tag> | overflow | none>",
+            )?;
+            let (filter_kind, filter_value) =
+                match (command_arr.get(4).copied(), command_arr.get(5).copied()) {
+                    (Some("item"), Some(item_id)) => (Some("item"), Some(item_id)),
+                    (Some("tag"), Some(tag)) => (Some("tag"), Some(tag)),
+                    (Some("overflow"), None) => (Some("overflow"), None),
+                    (Some("none"), None) => (None, None),
+                    _ => {
+                        bot.chat(
+                            "Usage: filter <x> <y> <z> <item <id> | tag <tag> | overflow | none>",
+                        );
+                        return Ok(());
+                    }
+                };
+            if set_chest_filter(pool, x, y, z, filter_kind, filter_value).await? {
+                bot.chat(&format!(
+                    "Set filter of ({}, {}, {}) to {} {}",
+                    x,
+                    y,
+                    z,
+                    filter_kind.unwrap_or("none"),
+                    filter_value.unwrap_or("")
+                ));
+            } else {
+                bot.chat(&format!(
+                    "No indexed storage block at ({}, {}, {})",
+                    x, y, z
+                ));
             }
-        };
+        }
+        _ => {
+            bot.chat("unknown command");
+        }
+    };
+    Ok(())
+}
+
+/// Reads the `<x> <y> <z>` after the command name
+fn parse_pos(command_arr: &[&str], usage: &str) -> Result<(f64, f64, f64), BotError> {
+    match command_arr.get(1..4) {
+        Some([x, y, z]) => Ok((x.parse()?, y.parse()?, z.parse()?)),
+        _ => Err(BotError::Parse(usage.to_string())),
     }
 }
 
-pub fn teleport_to(bot: &azalea::Client, x: i32, y: i32, z: i32) {
+pub fn teleport_to(bot: &azalea::Client, x: i32, y: i32, z: i32) -> Result<(), BotError> {
     bot.write_packet(ServerboundGamePacket::MovePlayerPos(
         ServerboundMovePlayerPosPacket {
             x: x as f64 + 0.5,
@@ -356,14 +913,412 @@ pub fn teleport_to(bot: &azalea::Client, x: i32, y: i32, z: i32) {
             on_ground: true,
         },
     ));
-    {
-        let mut ecs = bot.ecs.lock();
-        let mut entity_mut = ecs.entity_mut(bot.entity);
-        let mut position = entity_mut.get_mut::<Position>().unwrap();
-        position.x = x as f64 + 0.5;
-        position.y = y as f64;
-        position.z = z as f64 + 0.5;
+    let mut ecs = bot.ecs.lock();
+    let mut entity_mut = ecs.entity_mut(bot.entity);
+    let Some(mut position) = entity_mut.get_mut::<Position>() else {
+        return Err(BotError::Movement(BlockPos { x, y, z }));
+    };
+    position.x = x as f64 + 0.5;
+    position.y = y as f64;
+    position.z = z as f64 + 0.5;
+    Ok(())
+}
+
+/// Takes up to `limit` of an item out of storage into the bot's inventory,
+/// returns how many were taken.
+/// Whole stacks are moved, so this can overshoot `limit` by up to one stack
+pub async fn take_from_storage(
+    bot: &mut azalea::Client,
+    pool: &PgPool,
+    region: &Region,
+    item_id: &str,
+    limit: Option<i32>,
+    stop: impl Fn() -> bool,
+) -> Result<i32, BotError> {
+    // reserve the stacks first so a job that runs in between doesn't plan on them
+    let locations = match Audit::current().job_id {
+        Some(job_id) => reserve_item(pool, job_id, item_id, limit.map(i64::from)).await?,
+        None => find_item(pool, item_id).await?,
+    };
+    // group the slots by barrel so each barrel is only opened once
+    let mut barrels: Vec<(BlockPos, Vec<i32>)> = vec![];
+    for location in locations {
+        let blockpos = BlockPos {
+            x: location.get::<f64, _>("x") as i32,
+            y: location.get::<f64, _>("y") as i32,
+            z: location.get::<f64, _>("z") as i32,
+        };
+        let slot = location.get::<i32, _>("location_in_chest");
+        match barrels.iter_mut().find(|(pos, _)| *pos == blockpos) {
+            Some((_, slots)) => slots.push(slot),
+            None => barrels.push((blockpos, vec![slot])),
+        }
+    }
+
+    let mut taken = 0;
+    'barrels: for (blockpos, slots) in barrels {
+        if limit.is_some_and(|limit| taken >= limit) || stop() {
+            break;
+        }
+        teleport_to(bot, blockpos.x, region.walking_level, blockpos.z)?;
+        let barrel = match get_storage_handle(bot, blockpos).await {
+            Some(barrel) => barrel,
+            None => {
+                println!("failed to open storage block at [{:?}] for an unknown reason (this is probably my fault)", blockpos);
+                continue;
+            }
+        };
+        let contents = match barrel.contents() {
+            Some(contents) => contents,
+            None => {
+                println!("Failed to get contents of chest at [{:?}]", blockpos);
+                continue;
+            }
+        };
+        let mut inventory_full = false;
+        for index in slots {
+            if limit.is_some_and(|limit| taken >= limit) {
+                break;
+            }
+            // the db can be out of date, so check the slot still has the item
+            let count = match contents.get(index as usize) {
+                Some(ItemSlot::Present(item)) if item.kind.to_string() == item_id => item.count,
+                _ => continue,
+            };
+            println!("clicking slot {index} at [{:?}]", blockpos);
+            let Some(transfer) = quick_move(&barrel, index as usize).await else {
+                return Err(BotError::ContainerOpen(blockpos));
+            };
+            taken += transfer.moved;
+            if transfer.arrived != transfer.moved {
+                bot.chat(&format!(
+                    "{} of {}x {} from [{:?}] didn't show up in my inventory",
+                    transfer.moved - transfer.arrived,
+                    transfer.moved,
+                    item_id,
+                    blockpos
+                ));
+            }
+            // the click only moves what fits, so anything left means the inventory is full
+            if transfer.left > 0 {
+                if transfer.moved > 0 {
+                    bot.chat(&format!(
+                        "Only took {} of {}x {} from [{:?}], my inventory is full",
+                        transfer.moved, count, item_id, blockpos
+                    ));
+                }
+                inventory_full = true;
+                break;
+            }
+        }
+        // record what the barrel holds now rather than what the clicks should have done
+        if let Some(contents) = barrel.contents() {
+            save_contents(pool, blockpos, &contents).await?;
+        }
+        drop(barrel);
+        record_inventory(bot, pool).await?;
+        if inventory_full {
+            if taken == 0 {
+                return Err(BotError::InventoryFull(item_id.to_string()));
+            }
+            break;
+        }
+    }
+    Ok(taken)
+}
+
+/// Puts every stack of the item the bot is holding into the depot,
+/// returns true if some of it didn't fit
+async fn deliver(
+    bot: &mut azalea::Client,
+    pool: &PgPool,
+    depot: &Depot,
+    item_id: &str,
+) -> Result<bool, BotError> {
+    teleport_to(bot, depot.x, depot.y, depot.z)?;
+    put_into_container(bot, depot.storage_pos(), |item| {
+        item.kind.to_string() == item_id
+    })
+    .await?;
+    record_inventory(bot, pool).await?;
+    Ok(inventory_item_ids(bot).iter().any(|held| held == item_id))
+}
+
+/// Puts whatever the bot is holding back into storage, and what doesn't fit there
+/// into the output depot. Returns the ids of items that are still held
+async fn empty_inventory(
+    bot: &mut azalea::Client,
+    worker: Worker<'_>,
+) -> Result<Vec<String>, BotError> {
+    if inventory_item_ids(bot).is_empty() {
+        return Ok(vec![]);
+    }
+    store_inventory(bot, worker.pool, worker.region).await?;
+    if !inventory_item_ids(bot).is_empty() {
+        if let Some(depot) = worker.config.depot(DepotKind::Output, None) {
+            teleport_to(bot, depot.x, depot.y, depot.z)?;
+            put_into_container(bot, depot.storage_pos(), |_| true).await?;
+        }
+    }
+    record_inventory(bot, worker.pool).await?;
+    Ok(inventory_item_ids(bot))
+}
+
+/// Saves what the bot is carrying, so the db knows about items between containers
+pub async fn record_inventory(bot: &azalea::Client, pool: &PgPool) -> Result<(), BotError> {
+    let menu = bot.menu();
+    let slots = menu.slots();
+    let held = menu
+        .player_slots_range()
+        .enumerate()
+        .filter_map(|(index, slot)| match &slots[slot] {
+            ItemSlot::Present(item) => {
+                Some((index as i32, item.kind.to_string(), item.count as i16))
+            }
+            ItemSlot::Empty => None,
+        })
+        .collect::<Vec<_>>();
+    save_bot_inventory(pool, &held).await?;
+    Ok(())
+}
+
+/// What a shift-click did, read back from the menu after `settle_clicks`
+pub struct Transfer {
+    /// How many left the clicked slot
+    pub moved: i32,
+    /// How many turned up on the other side of the menu, normally the same as `moved`
+    pub arrived: i32,
+    /// How many are still in the clicked slot
+    pub left: i32,
+}
+
+/// Gives the server `clicks.settle_millis` to send back the slots a click changed.
+/// Until it does, the local menu only holds azalea's prediction of what the click did.
+/// Nothing confirms the answer arrived, a server lagging more than that still gets
+/// the prediction read back
+pub async fn settle_clicks() {
+    tokio::time::sleep(Duration::from_millis(CONFIG.clicks.settle_millis)).await;
+}
+
+/// Shift-clicks a slot and compares both sides of the menu before and after, once the
+/// server has had time to answer. Clicks again a couple of times if the menu says
+/// nothing moved, in case the first one got lost
+pub async fn quick_move(container: &ContainerHandle, slot: usize) -> Option<Transfer> {
+    let before = container.menu()?;
+    let item = before.slots().get(slot)?.clone();
+    let ItemSlot::Present(item) = item else {
+        return Some(Transfer {
+            moved: 0,
+            arrived: 0,
+            left: 0,
+        });
+    };
+    let player_slots = before.player_slots_range();
+    // items go from the container to the inventory or the other way round
+    let destination = if player_slots.contains(&slot) {
+        0..player_slots.start
+    } else {
+        player_slots
+    };
+    let count_at_destination = |slots: &[ItemSlot]| -> i32 {
+        slots[destination.clone()]
+            .iter()
+            .filter(|other| other.kind() == item.kind)
+            .map(|other| other.count() as i32)
+            .sum()
+    };
+    let arrived_before = count_at_destination(&before.slots());
+
+    let mut after = before;
+    for _ in 0..3 {
+        container.click(QuickMoveClick::Left { slot: slot as u16 });
+        settle_clicks().await;
+        after = container.menu()?;
+        if after.slots().get(slot).map(|other| other.count()) != Some(item.count) {
+            break;
+        }
+    }
+    let slots = after.slots();
+    let left = match slots.get(slot) {
+        Some(ItemSlot::Present(other)) if other.kind == item.kind => other.count as i32,
+        _ => 0,
+    };
+    Some(Transfer {
+        moved: item.count as i32 - left,
+        arrived: count_at_destination(&slots) - arrived_before,
+        left,
+    })
+}
+
+/// Moves everything in the bot's inventory into storage, honoring barrel filters,
+/// returns the ids of items that didn't fit anywhere
+pub async fn store_inventory(
+    bot: &mut azalea::Client,
+    pool: &PgPool,
+    region: &Region,
+) -> Result<Vec<String>, BotError> {
+    let air_id = azalea::Item::Air.to_string();
+    let mut leftover = vec![];
+    for item_id in inventory_item_ids(bot) {
+        let mut stored = false;
+        for candidate in deposit_candidates(pool, &item_id, &air_id).await? {
+            let blockpos = BlockPos {
+                x: candidate.get::<f64, _>("x") as i32,
+                y: candidate.get::<f64, _>("y") as i32,
+                z: candidate.get::<f64, _>("z") as i32,
+            };
+            teleport_to(bot, blockpos.x, region.walking_level, blockpos.z)?;
+            let barrel = match get_storage_handle(bot, blockpos).await {
+                Some(barrel) => barrel,
+                None => {
+                    println!("failed to open storage block at [{:?}] for an unknown reason (this is probably my fault)", blockpos);
+                    continue;
+                }
+            };
+            let Some(menu) = barrel.menu() else {
+                continue;
+            };
+            let slots = menu.slots();
+            for slot in menu.player_slots_range() {
+                if slots[slot].kind().to_string() == item_id {
+                    barrel.click(QuickMoveClick::Left { slot: slot as u16 });
+                }
+            }
+
+            // the local menu only has azalea's guess at what the clicks did until the
+            // server sends the slots back, so give it time before recording the barrel
+            settle_clicks().await;
+            let Some(menu) = barrel.menu() else {
+                continue;
+            };
+            let slots = menu.slots();
+            save_contents(pool, blockpos, &slots[..menu.player_slots_range().start]).await?;
+            if !menu
+                .player_slots_range()
+                .any(|slot| slots[slot].kind().to_string() == item_id)
+            {
+                stored = true;
+                break;
+            }
+        }
+        if !stored {
+            leftover.push(item_id);
+        }
+    }
+    record_inventory(bot, pool).await?;
+    Ok(leftover)
+}
+
+/// Overwrites the db's record of a storage block with its actual contents
+pub async fn save_contents(
+    pool: &PgPool,
+    blockpos: BlockPos,
+    contents: &[ItemSlot],
+) -> Result<(), BotError> {
+    let slots = contents
+        .iter()
+        .map(|slot| (slot.kind().to_string(), slot.count() as i16))
+        .collect::<Vec<_>>();
+    save_chest_contents(
+        pool,
+        blockpos.x as f64,
+        blockpos.y as f64,
+        blockpos.z as f64,
+        &slots,
+        &Audit::current(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Distinct ids of the items in the bot's inventory
+pub fn inventory_item_ids(bot: &azalea::Client) -> Vec<String> {
+    let menu = bot.menu();
+    let slots = menu.slots();
+    let mut item_ids: Vec<String> = vec![];
+    for slot in menu.player_slots_range() {
+        if let ItemSlot::Present(item) = &slots[slot] {
+            let item_id = item.kind.to_string();
+            if !item_ids.contains(&item_id) {
+                item_ids.push(item_id);
+            }
+        }
+    }
+    item_ids
+}
+
+/// Shift-clicks every item in the bot's inventory that `accept` allows into the container,
+/// returns the number of stacks moved
+pub async fn put_into_container(
+    bot: &mut azalea::Client,
+    blockpos: BlockPos,
+    accept: impl Fn(&ItemSlotData) -> bool,
+) -> Result<usize, BotError> {
+    let container = get_storage_handle(bot, blockpos)
+        .await
+        .ok_or(BotError::ContainerOpen(blockpos))?;
+    let menu = container.menu().ok_or(BotError::ContainerOpen(blockpos))?;
+    let slots = menu.slots();
+    let mut moved = 0;
+    for slot in menu.player_slots_range() {
+        if let Some(ItemSlot::Present(item)) = slots.get(slot) {
+            if accept(item)
+                && quick_move(&container, slot)
+                    .await
+                    .is_some_and(|transfer| transfer.moved > 0)
+            {
+                moved += 1;
+            }
+        }
+    }
+    Ok(moved)
+}
+
+/// Counts how many of an item are in a container
+pub async fn count_in_container(
+    bot: &mut azalea::Client,
+    blockpos: BlockPos,
+    item_id: &str,
+) -> Result<i32, BotError> {
+    let container = get_storage_handle(bot, blockpos)
+        .await
+        .ok_or(BotError::ContainerOpen(blockpos))?;
+    let contents = container
+        .contents()
+        .ok_or(BotError::ContainerOpen(blockpos))?;
+    Ok(contents
+        .iter()
+        .filter(|slot| slot.kind().to_string() == item_id)
+        .map(|slot| slot.count() as i32)
+        .sum())
+}
+
+/// Shift-clicks every item in the depot that `accept` allows into the bot's inventory,
+/// returns the number of stacks moved
+pub async fn take_from_depot(
+    bot: &mut azalea::Client,
+    depot: &Depot,
+    accept: impl Fn(&ItemSlotData) -> bool,
+) -> Result<usize, BotError> {
+    teleport_to(bot, depot.x, depot.y, depot.z)?;
+    let blockpos = depot.storage_pos();
+    let barrel = get_storage_handle(bot, blockpos)
+        .await
+        .ok_or(BotError::ContainerOpen(blockpos))?;
+    let contents = barrel.contents().ok_or(BotError::ContainerOpen(blockpos))?;
+    let mut moved = 0;
+    for (index, slot) in contents.iter().enumerate() {
+        println!("Checking slot {index}: {slot:?}");
+        if let ItemSlot::Present(item) = slot {
+            if !accept(item) {
+                continue;
+            }
+            bot.chat(&format!("found item: [{} x{}]", item.kind, item.count));
+            barrel.click(QuickMoveClick::Left { slot: index as u16 });
+            moved += 1;
+        }
     }
+    Ok(moved)
 }
 
 pub async fn get_storage_handle(
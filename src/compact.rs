@@ -0,0 +1,238 @@
+use azalea::BlockPos;
+use azalea_inventory::item::MaxStackSizeExt;
+use azalea_inventory::operations::PickupClick;
+use sqlx::{PgPool, Row};
+
+use crate::{
+    bot_handle_queue::{
+        get_storage_handle, quick_move, save_contents, settle_clicks, store_inventory, teleport_to,
+    },
+    config::Region,
+    error::BotError,
+    jobs::{Job, JobQueue},
+    postgres::mergeable_stacks,
+    reports::item_from_id,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackLocation {
+    pub pos: BlockPos,
+    pub slot: i32,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompactMove {
+    pub item_id: String,
+    pub from: StackLocation,
+    pub to: StackLocation,
+    pub count: i32,
+}
+
+/// Partial stacks of one item that will be merged
+#[derive(Debug, Clone)]
+pub struct CompactGroup {
+    pub item_id: String,
+    pub max_stack_size: i32,
+    /// Stacks that get emptied
+    pub sources: Vec<StackLocation>,
+    /// Stacks that get topped up
+    pub targets: Vec<StackLocation>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompactPlan {
+    pub groups: Vec<CompactGroup>,
+    pub moves: Vec<CompactMove>,
+}
+
+impl CompactPlan {
+    pub fn freed_slots(&self) -> impl Iterator<Item = &StackLocation> {
+        self.groups.iter().flat_map(|group| group.sources.iter())
+    }
+}
+
+/// Finds partial stacks that can be merged and works out the fewest moves to merge them
+pub async fn plan_compaction(pool: &PgPool) -> Result<CompactPlan, sqlx::Error> {
+    // (item_id, nbt) -> partial stacks, fullest first since that's how the query orders them
+    let mut stacks: Vec<((String, Vec<u8>), Vec<(StackLocation, i32)>)> = vec![];
+    for row in mergeable_stacks(pool).await? {
+        let key = (
+            row.get::<String, _>("item_id"),
+            row.get::<Vec<u8>, _>("item_nbt"),
+        );
+        let location = StackLocation {
+            pos: BlockPos {
+                x: row.get::<f64, _>("x") as i32,
+                y: row.get::<f64, _>("y") as i32,
+                z: row.get::<f64, _>("z") as i32,
+            },
+            slot: row.get::<i32, _>("location_in_chest"),
+        };
+        let count = row.get::<i16, _>("item_count") as i32;
+        match stacks.last_mut() {
+            Some((last_key, group)) if *last_key == key => group.push((location, count)),
+            _ => stacks.push((key, vec![(location, count)])),
+        }
+    }
+
+    let mut plan = CompactPlan::default();
+    for ((item_id, _), group) in stacks {
+        let Some(item) = item_from_id(&item_id) else {
+            continue;
+        };
+        let max_stack_size = item.max_stack_size() as i32;
+        let partial = group
+            .into_iter()
+            .filter(|(_, count)| *count < max_stack_size)
+            .collect::<Vec<_>>();
+        let total: i32 = partial.iter().map(|(_, count)| count).sum();
+        let needed = total.div_ceil(max_stack_size) as usize;
+        if needed >= partial.len() {
+            continue;
+        }
+
+        // keep the fullest stacks and pour the rest into them
+        let (targets, sources) = partial.split_at(needed);
+        let mut room = targets
+            .iter()
+            .map(|(location, count)| (*location, max_stack_size - count))
+            .collect::<Vec<_>>();
+        for (from, count) in sources {
+            let mut remaining = *count;
+            for (to, space) in room.iter_mut() {
+                if remaining == 0 {
+                    break;
+                }
+                let moved = remaining.min(*space);
+                if moved == 0 {
+                    continue;
+                }
+                *space -= moved;
+                remaining -= moved;
+                plan.moves.push(CompactMove {
+                    item_id: item_id.clone(),
+                    from: *from,
+                    to: *to,
+                    count: moved,
+                });
+            }
+        }
+
+        plan.groups.push(CompactGroup {
+            item_id,
+            max_stack_size,
+            sources: sources.iter().map(|(location, _)| *location).collect(),
+            targets: targets.iter().map(|(location, _)| *location).collect(),
+        });
+    }
+    Ok(plan)
+}
+
+/// Carries out a compaction plan through the bot's inventory, keeping the db in sync.
+/// Anything left in the inventory afterwards is put back into storage.
+/// Stops early between items if a job that outranks `job` is waiting, or it was paused
+/// or cancelled, returns whether the whole plan was carried out
+pub async fn run_compaction(
+    bot: &mut azalea::Client,
+    pool: &PgPool,
+    region: &Region,
+    plan: &CompactPlan,
+    queue: &JobQueue,
+    job: &Job,
+) -> Result<bool, BotError> {
+    let mut finished = true;
+    for (index, group) in plan.groups.iter().enumerate() {
+        queue.set_progress(job, index, plan.groups.len(), "item");
+        if index > 0
+            && (queue.has_waiting_above(job.priority)
+                || queue.is_paused()
+                || queue.is_cancelled(job.job_id))
+        {
+            finished = false;
+            break;
+        }
+        for (pos, slots) in by_barrel(&group.sources) {
+            teleport_to(bot, pos.x, region.walking_level, pos.z)?;
+            let Some(barrel) = get_storage_handle(bot, pos).await else {
+                println!("failed to open storage block at [{:?}] for an unknown reason (this is probably my fault)", pos);
+                continue;
+            };
+            for slot in slots {
+                quick_move(&barrel, slot as usize).await;
+            }
+            if let Some(contents) = barrel.contents() {
+                save_contents(pool, pos, &contents).await?;
+            }
+        }
+
+        for (pos, slots) in by_barrel(&group.targets) {
+            teleport_to(bot, pos.x, region.walking_level, pos.z)?;
+            let Some(barrel) = get_storage_handle(bot, pos).await else {
+                println!("failed to open storage block at [{:?}] for an unknown reason (this is probably my fault)", pos);
+                continue;
+            };
+            for slot in slots {
+                loop {
+                    let Some(menu) = barrel.menu() else {
+                        break;
+                    };
+                    let all_slots = menu.slots();
+                    let target = &all_slots[slot as usize];
+                    if target.kind().to_string() != group.item_id
+                        || target.count() as i32 >= group.max_stack_size
+                    {
+                        break;
+                    }
+                    let Some(source) = menu
+                        .player_slots_range()
+                        .find(|&i| all_slots[i].kind().to_string() == group.item_id)
+                    else {
+                        break;
+                    };
+                    let before = target.count();
+
+                    // pick the stack up, drop as much as fits onto the target, put the rest back
+                    barrel.click(PickupClick::Left {
+                        slot: Some(source as u16),
+                    });
+                    barrel.click(PickupClick::Left {
+                        slot: Some(slot as u16),
+                    });
+                    barrel.click(PickupClick::Left {
+                        slot: Some(source as u16),
+                    });
+                    // read the target back once the server has had time to answer
+                    settle_clicks().await;
+
+                    let after = barrel
+                        .menu()
+                        .map(|menu| menu.slots()[slot as usize].count())
+                        .unwrap_or(before);
+                    if after == before {
+                        break;
+                    }
+                }
+            }
+            if let Some(contents) = barrel.contents() {
+                save_contents(pool, pos, &contents).await?;
+            }
+        }
+    }
+
+    let leftover = store_inventory(bot, pool, region).await?;
+    if !leftover.is_empty() {
+        bot.chat(&format!("No room in storage for {}", leftover.join(", ")));
+    }
+    Ok(finished)
+}
+
+fn by_barrel(locations: &[StackLocation]) -> Vec<(BlockPos, Vec<i32>)> {
+    let mut barrels: Vec<(BlockPos, Vec<i32>)> = vec![];
+    for location in locations {
+        match barrels.iter_mut().find(|(pos, _)| *pos == location.pos) {
+            Some((_, slots)) => slots.push(location.slot),
+            None => barrels.push((location.pos, vec![location.slot])),
+        }
+    }
+    barrels
+}
@@ -1,4 +1,5 @@
 use azalea::prelude::*;
+use azalea::BlockPos;
 use lazy_static::lazy_static;
 use serde::Deserialize;
 
@@ -7,7 +8,22 @@ pub struct Config {
     pub bot_owner: String,
     pub connections: Connections,
     pub region: Region,
-    pub depot: Depot,
+    pub depots: Vec<Depot>,
+    #[serde(default)]
+    pub exporters: Vec<Exporter>,
+    #[serde(default)]
+    pub stations: Vec<Station>,
+    #[serde(default)]
+    pub smelting: Smelting,
+    #[serde(default)]
+    pub queue: Queue,
+    #[serde(default)]
+    pub history: History,
+    #[serde(default)]
+    pub alerts: Alerts,
+    #[serde(default)]
+    pub clicks: Clicks,
+    pub packing: Option<Packing>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -27,8 +43,23 @@ pub struct Region {
     pub max_y: i32,
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DepotKind {
+    /// Players drop items here for `deposit` to pick up
+    Input,
+    /// `withdraw` delivers items here
+    Output,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Depot {
+    pub name: String,
+    pub kind: DepotKind,
+    /// Player this depot belongs to, lets `withdraw <item> <player>` pick their depot
+    pub owner: Option<String>,
+    /// Only used by input depots
+    pub importer: Option<Importer>,
     pub storage_x: i32,
     pub storage_y: i32,
     pub storage_z: i32,
@@ -37,6 +68,339 @@ pub struct Depot {
     pub z: i32,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct Importer {
+    pub interval_secs: u64,
+    /// Wait until no other commands are queued or running
+    #[serde(default)]
+    pub idle_only: bool,
+    #[serde(default)]
+    pub whitelist: Vec<String>,
+    #[serde(default)]
+    pub blacklist: Vec<String>,
+}
+
+impl Importer {
+    pub fn accepts(&self, item_id: &str) -> bool {
+        (self.whitelist.is_empty() || self.whitelist.iter().any(|id| id == item_id))
+            && !self.blacklist.iter().any(|id| id == item_id)
+    }
+}
+
+impl Depot {
+    pub fn storage_pos(&self) -> BlockPos {
+        BlockPos {
+            x: self.storage_x,
+            y: self.storage_y,
+            z: self.storage_z,
+        }
+    }
+}
+
+/// Keeps a container stocked with `count` of `item`
+#[derive(Deserialize, Debug, Clone)]
+pub struct Exporter {
+    pub name: String,
+    pub item: String,
+    pub count: i32,
+    pub interval_secs: u64,
+    pub container_x: i32,
+    pub container_y: i32,
+    pub container_z: i32,
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl Exporter {
+    pub fn container_pos(&self) -> BlockPos {
+        BlockPos {
+            x: self.container_x,
+            y: self.container_y,
+            z: self.container_z,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StationKind {
+    CraftingTable,
+    Stonecutter,
+    SmithingTable,
+    Furnace,
+    BlastFurnace,
+    Smoker,
+}
+
+impl StationKind {
+    pub const ALL: [StationKind; 6] = [
+        StationKind::CraftingTable,
+        StationKind::Stonecutter,
+        StationKind::SmithingTable,
+        StationKind::Furnace,
+        StationKind::BlastFurnace,
+        StationKind::Smoker,
+    ];
+
+    /// Name used for the `station` column of recipes
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StationKind::CraftingTable => "crafting_table",
+            StationKind::Stonecutter => "stonecutter",
+            StationKind::SmithingTable => "smithing_table",
+            StationKind::Furnace => "furnace",
+            StationKind::BlastFurnace => "blast_furnace",
+            StationKind::Smoker => "smoker",
+        }
+    }
+
+    pub fn is_furnace(&self) -> bool {
+        matches!(
+            self,
+            StationKind::Furnace | StationKind::BlastFurnace | StationKind::Smoker
+        )
+    }
+
+    /// Seconds it takes to smelt one item
+    pub fn cook_secs(&self) -> u64 {
+        match self {
+            StationKind::BlastFurnace | StationKind::Smoker => 5,
+            _ => 10,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<StationKind> {
+        StationKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == name)
+    }
+
+    /// Menu slot that a recipe's ingredient slot goes into
+    pub fn input_slot(&self, slot: i32) -> usize {
+        match self {
+            // slot 0 is the result, the grid comes after it
+            StationKind::CraftingTable => slot as usize + 1,
+            StationKind::Stonecutter => 0,
+            // template, base, addition
+            StationKind::SmithingTable => slot as usize,
+            // ingredient, fuel
+            StationKind::Furnace | StationKind::BlastFurnace | StationKind::Smoker => slot as usize,
+        }
+    }
+
+    pub fn result_slot(&self) -> usize {
+        match self {
+            StationKind::CraftingTable => 0,
+            StationKind::Stonecutter => 1,
+            StationKind::SmithingTable => 3,
+            StationKind::Furnace | StationKind::BlastFurnace | StationKind::Smoker => 2,
+        }
+    }
+
+    /// How many ingredient slots recipes for this station can have
+    pub fn input_count(&self) -> usize {
+        match self {
+            StationKind::CraftingTable => 9,
+            StationKind::Stonecutter => 1,
+            StationKind::SmithingTable => 3,
+            StationKind::Furnace | StationKind::BlastFurnace | StationKind::Smoker => 1,
+        }
+    }
+}
+
+/// Limits on how many jobs can be waiting, new jobs are refused past these
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Queue {
+    pub max_pending: usize,
+    pub max_per_requester: usize,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Queue {
+            max_pending: 100,
+            max_per_requester: 20,
+        }
+    }
+}
+
+/// Who gets low-stock alerts besides subscribed websocket clients
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Alerts {
+    /// Players that get alerts whispered to them
+    pub players: Vec<String>,
+    /// How far above its threshold an item has to get before it can alert again
+    pub hysteresis_percent: i64,
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Alerts {
+            players: vec![],
+            hysteresis_percent: 10,
+        }
+    }
+}
+
+/// How the bot reads back what its clicks in a container did
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Clicks {
+    /// How long to wait after clicking before reading the menu, should be more
+    /// than the server's round trip
+    pub settle_millis: u64,
+}
+
+impl Default for Clicks {
+    fn default() -> Self {
+        Clicks { settle_millis: 150 }
+    }
+}
+
+/// How long the history of storage changes and stock levels is kept, 0 keeps it forever
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct History {
+    pub retention_days: i32,
+    pub prune_interval_secs: u64,
+    pub snapshot_interval_secs: u64,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            retention_days: 90,
+            prune_interval_secs: 3600,
+            snapshot_interval_secs: 3600,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Smelting {
+    pub fuel: String,
+    pub items_per_fuel: i32,
+    /// How often furnaces get checked for finished items
+    pub poll_secs: u64,
+}
+
+impl Default for Smelting {
+    fn default() -> Self {
+        Smelting {
+            fuel: "minecraft:coal".to_string(),
+            items_per_fuel: 8,
+            poll_secs: 5,
+        }
+    }
+}
+
+/// A block the bot crafts or smelts at,
+/// there can be several furnaces of each kind to smelt in parallel
+#[derive(Deserialize, Debug, Clone)]
+pub struct Station {
+    pub kind: StationKind,
+    pub block_x: i32,
+    pub block_y: i32,
+    pub block_z: i32,
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl Station {
+    pub fn block_pos(&self) -> BlockPos {
+        BlockPos {
+            x: self.block_x,
+            y: self.block_y,
+            z: self.block_z,
+        }
+    }
+}
+
+/// Where the bot places shulker boxes to fill them for packed withdraws
+#[derive(Deserialize, Debug, Clone)]
+pub struct Packing {
+    /// Has to be air with a solid block below it, the box gets placed on top of that block
+    pub block_x: i32,
+    pub block_y: i32,
+    pub block_z: i32,
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+    /// Withdraws of at least this many stacks get packed even without asking
+    pub min_stacks: Option<i32>,
+    /// Unpack shulker boxes dropped in input depots and store what was in them
+    #[serde(default)]
+    pub unpack_deposits: bool,
+    /// Where unpacked boxes go, they're stored like any other item without one.
+    /// Packed boxes no output depot had room for go here too
+    pub shulker_barrel: Option<ShulkerBarrel>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ShulkerBarrel {
+    pub block_x: i32,
+    pub block_y: i32,
+    pub block_z: i32,
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl ShulkerBarrel {
+    pub fn block_pos(&self) -> BlockPos {
+        BlockPos {
+            x: self.block_x,
+            y: self.block_y,
+            z: self.block_z,
+        }
+    }
+}
+
+impl Packing {
+    pub fn block_pos(&self) -> BlockPos {
+        BlockPos {
+            x: self.block_x,
+            y: self.block_y,
+            z: self.block_z,
+        }
+    }
+}
+
+impl Config {
+    /// Finds a depot of the given kind by depot name or owner,
+    /// falling back to the first depot of that kind without an owner
+    pub fn depot(&self, kind: DepotKind, name: Option<&str>) -> Option<&Depot> {
+        match name {
+            Some(name) => self.depots.iter().find(|depot| {
+                depot.kind == kind && (depot.name == name || depot.owner.as_deref() == Some(name))
+            }),
+            None => self
+                .depots
+                .iter()
+                .find(|depot| depot.kind == kind && depot.owner.is_none()),
+        }
+    }
+
+    pub fn depot_by_name(&self, name: &str) -> Option<&Depot> {
+        self.depots.iter().find(|depot| depot.name == name)
+    }
+
+    pub fn exporter(&self, name: &str) -> Option<&Exporter> {
+        self.exporters.iter().find(|exporter| exporter.name == name)
+    }
+
+    pub fn station(&self, kind: &str) -> Option<&Station> {
+        self.stations
+            .iter()
+            .find(|station| station.kind.as_str() == kind)
+    }
+}
+
 lazy_static! {
     pub static ref CONFIG: Config =
         toml::from_str(&std::fs::read_to_string("config.toml").unwrap()).unwrap();
@@ -0,0 +1,434 @@
+use std::{collections::HashMap, time::Duration};
+
+use azalea::container::ContainerHandle;
+use azalea::protocol::packets::game::{
+    serverbound_container_button_click_packet::ServerboundContainerButtonClickPacket,
+    ServerboundGamePacket,
+};
+use azalea_inventory::item::MaxStackSizeExt;
+use azalea_inventory::operations::{PickupClick, QuickMoveClick};
+use sqlx::{PgPool, Row};
+
+use crate::{
+    bot_handle_queue::{get_storage_handle, store_inventory, take_from_storage, teleport_to},
+    config::{Region, StationKind, CONFIG},
+    error::BotError,
+    postgres::item_totals,
+    recipes::{Recipe, RecipeBook},
+    reports::item_from_id,
+    smelting::smelt_step,
+};
+
+/// How deep the planner follows ingredients that need crafting themselves
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct CraftStep {
+    pub recipe: Recipe,
+    /// How many times the recipe is crafted
+    pub times: i32,
+    /// The item picked for each ingredient, by crafting grid slot
+    pub inputs: Vec<(i32, String)>,
+    /// Stonecutter button that selects the recipe, `None` if it couldn't be worked out
+    pub button: Option<u8>,
+    /// Fuel and how much of it, for furnace recipes
+    pub fuel: Option<(String, i32)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CraftPlan {
+    /// Items taken out of storage
+    pub withdraw: Vec<(String, i32)>,
+    /// Crafts in the order they have to happen
+    pub steps: Vec<CraftStep>,
+    /// Items that are neither in storage nor craftable
+    pub missing: Vec<(String, i32)>,
+}
+
+impl CraftPlan {
+    pub fn describe(items: &[(String, i32)]) -> String {
+        items
+            .iter()
+            .map(|(item_id, count)| format!("{}x {}", count, item_id))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// One line summary of everything the plan withdraws and crafts
+    pub fn summary(&self) -> String {
+        let crafts = self
+            .steps
+            .iter()
+            .map(|step| {
+                format!(
+                    "{}x {}",
+                    step.times * step.recipe.output_count,
+                    step.recipe.output_item
+                )
+            })
+            .collect::<Vec<_>>();
+        if crafts.is_empty() {
+            format!("withdraw {}", CraftPlan::describe(&self.withdraw))
+        } else {
+            format!(
+                "withdraw {}, then craft {}",
+                CraftPlan::describe(&self.withdraw),
+                crafts.join(", ")
+            )
+        }
+    }
+}
+
+/// Resolves a recipe tree against what's in storage
+#[derive(Clone)]
+pub struct Planner<'a> {
+    book: &'a RecipeBook,
+    /// Stations that are set up in the config, recipes for other stations are skipped
+    stations: Vec<String>,
+    stock: HashMap<String, i64>,
+    /// Extra output from earlier crafts in the plan
+    surplus: HashMap<String, i64>,
+    visiting: Vec<String>,
+    plan: CraftPlan,
+}
+
+impl<'a> Planner<'a> {
+    pub fn new(book: &'a RecipeBook, stock: HashMap<String, i64>) -> Self {
+        Planner {
+            book,
+            stations: CONFIG
+                .stations
+                .iter()
+                .map(|station| station.kind.as_str().to_string())
+                .collect(),
+            stock,
+            surplus: HashMap::new(),
+            visiting: vec![],
+            plan: CraftPlan::default(),
+        }
+    }
+
+    /// Loads what's in storage and not reserved by a running job as the stock to plan against
+    pub async fn load_stock(pool: &PgPool) -> Result<HashMap<String, i64>, sqlx::Error> {
+        Ok(item_totals(pool, i64::MAX)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let available = row.get::<i64, _>("total") - row.get::<i64, _>("reserved");
+                (row.get("item_id"), available)
+            })
+            .collect())
+    }
+
+    /// Plans getting `count` of an item, using what's in storage first and crafting the rest
+    pub fn plan(mut self, item_id: &str, count: i32) -> CraftPlan {
+        self.need(item_id, count);
+        self.plan
+    }
+
+    /// Plans crafting `count` of an item, ingredients come from storage or get crafted too
+    pub fn plan_craft(self, item_id: &str, count: i32) -> CraftPlan {
+        self.plan_at(item_id, count, |_| true)
+    }
+
+    /// Plans smelting `count` of an item, ingredients can still be crafted
+    pub fn plan_smelt(self, item_id: &str, count: i32) -> CraftPlan {
+        self.plan_at(item_id, count, |station| station.is_furnace())
+    }
+
+    fn plan_at(
+        mut self,
+        item_id: &str,
+        count: i32,
+        station: impl Fn(StationKind) -> bool,
+    ) -> CraftPlan {
+        let recipe = self.pick_recipe_where(item_id, count, |recipe| {
+            StationKind::from_name(&recipe.station).is_some_and(&station)
+        });
+        match recipe {
+            Some(recipe) => self.craft(recipe, item_id, count),
+            None => add_to(&mut self.plan.missing, item_id, count),
+        }
+        self.plan
+    }
+
+    fn need(&mut self, item_id: &str, count: i32) {
+        let mut remaining = count;
+
+        let surplus = self.surplus.entry(item_id.to_string()).or_default();
+        let from_surplus = (*surplus).min(remaining as i64) as i32;
+        *surplus -= from_surplus as i64;
+        remaining -= from_surplus;
+
+        let stock = self.stock.entry(item_id.to_string()).or_default();
+        let from_stock = (*stock).min(remaining as i64) as i32;
+        *stock -= from_stock as i64;
+        remaining -= from_stock;
+        if from_stock > 0 {
+            add_to(&mut self.plan.withdraw, item_id, from_stock);
+        }
+
+        if remaining == 0 {
+            return;
+        }
+        if self.visiting.iter().any(|id| id == item_id) || self.visiting.len() >= MAX_DEPTH {
+            add_to(&mut self.plan.missing, item_id, remaining);
+            return;
+        }
+        match self.pick_recipe(item_id, remaining) {
+            Some(recipe) => self.craft(recipe, item_id, remaining),
+            None => add_to(&mut self.plan.missing, item_id, remaining),
+        }
+    }
+
+    fn craft(&mut self, recipe: &Recipe, item_id: &str, count: i32) {
+        let times = count.div_ceil(recipe.output_count);
+
+        self.visiting.push(item_id.to_string());
+        let mut inputs = vec![];
+        for ingredient in &recipe.ingredients {
+            let choice = self.pick_choice(&ingredient.choices);
+            self.need(&choice, times);
+            inputs.push((ingredient.slot, choice));
+        }
+        self.visiting.pop();
+
+        let extra = times * recipe.output_count - count;
+        if extra > 0 {
+            *self.surplus.entry(item_id.to_string()).or_default() += extra as i64;
+        }
+        let fuel = match StationKind::from_name(&recipe.station) {
+            Some(station) if station.is_furnace() => {
+                let fuel = CONFIG.smelting.fuel.clone();
+                let fuel_count = times.div_ceil(CONFIG.smelting.items_per_fuel);
+                self.need(&fuel, fuel_count);
+                Some((fuel, fuel_count))
+            }
+            _ => None,
+        };
+        let button = match (recipe.station.as_str(), inputs.first()) {
+            ("stonecutter", Some((_, input))) => {
+                self.book.stonecutter_button(input, &recipe.output_item)
+            }
+            _ => None,
+        };
+        self.plan.steps.push(CraftStep {
+            recipe: recipe.clone(),
+            times,
+            inputs,
+            button,
+            fuel,
+        });
+    }
+
+    /// Prefers the first recipe that can be made with what's in storage,
+    /// saved patterns come before vanilla recipes
+    fn pick_recipe(&self, item_id: &str, count: i32) -> Option<&'a Recipe> {
+        self.pick_recipe_where(item_id, count, |_| true)
+    }
+
+    fn pick_recipe_where(
+        &self,
+        item_id: &str,
+        count: i32,
+        accept: impl Fn(&Recipe) -> bool,
+    ) -> Option<&'a Recipe> {
+        let recipes = self
+            .book
+            .recipes_for(item_id)
+            .iter()
+            .filter(|recipe| self.stations.contains(&recipe.station) && accept(*recipe))
+            .collect::<Vec<_>>();
+        recipes
+            .iter()
+            .find(|recipe| {
+                let mut trial = self.clone();
+                trial.plan = CraftPlan::default();
+                trial.craft(recipe, item_id, count);
+                trial.plan.missing.is_empty()
+            })
+            .or(recipes.first())
+            .copied()
+    }
+
+    /// Picks whichever allowed item there's the most of
+    fn pick_choice(&self, choices: &[String]) -> String {
+        let item_ids = choices
+            .iter()
+            .flat_map(|choice| self.book.expand(choice))
+            .collect::<Vec<_>>();
+        item_ids
+            .iter()
+            .max_by_key(|item_id| {
+                self.stock.get(*item_id).copied().unwrap_or(0)
+                    + self.surplus.get(*item_id).copied().unwrap_or(0)
+            })
+            .cloned()
+            .unwrap_or_else(|| choices[0].clone())
+    }
+}
+
+fn add_to(items: &mut Vec<(String, i32)>, item_id: &str, count: i32) {
+    match items.iter_mut().find(|(id, _)| id == item_id) {
+        Some((_, total)) => *total += count,
+        None => items.push((item_id.to_string(), count)),
+    }
+}
+
+/// Withdraws the plan's ingredients, crafts every step and puts the results into storage
+pub async fn run_craft_plan(
+    bot: &mut azalea::Client,
+    pool: &PgPool,
+    region: &Region,
+    plan: &CraftPlan,
+) -> Result<(), BotError> {
+    craft_into_inventory(bot, pool, region, plan).await?;
+
+    let leftover = store_inventory(bot, pool, region).await?;
+    if !leftover.is_empty() {
+        bot.chat(&format!("No room in storage for {}", leftover.join(", ")));
+    }
+    Ok(())
+}
+
+/// Withdraws the plan's ingredients and crafts every step,
+/// leaving the results in the bot's inventory
+pub async fn craft_into_inventory(
+    bot: &mut azalea::Client,
+    pool: &PgPool,
+    region: &Region,
+    plan: &CraftPlan,
+) -> Result<(), BotError> {
+    // check before anything gets withdrawn
+    if let Some(step) = plan
+        .steps
+        .iter()
+        .find(|step| step.recipe.station == "stonecutter" && step.button.is_none())
+    {
+        return Err(BotError::Craft(format!(
+            "no stonecutter button found for {}",
+            step.recipe.recipe_id
+        )));
+    }
+    for (item_id, count) in &plan.withdraw {
+        take_from_storage(bot, pool, region, item_id, Some(*count), || false).await?;
+    }
+
+    for step in &plan.steps {
+        let Some(station) = CONFIG.station(&step.recipe.station) else {
+            return Err(BotError::NoStation(step.recipe.station.clone()));
+        };
+        if station.kind.is_furnace() {
+            smelt_step(bot, station.kind, step).await?;
+            continue;
+        }
+        teleport_to(bot, station.x, station.y, station.z)?;
+        let Some(table) = get_storage_handle(bot, station.block_pos()).await else {
+            return Err(BotError::ContainerOpen(station.block_pos()));
+        };
+        craft_step(bot, &table, station.kind, step).await?;
+    }
+    Ok(())
+}
+
+/// Fills the station's inputs and shift-clicks the result, in batches of up to a stack
+async fn craft_step(
+    bot: &azalea::Client,
+    table: &ContainerHandle,
+    station: StationKind,
+    step: &CraftStep,
+) -> Result<(), BotError> {
+    let batch_size = step
+        .inputs
+        .iter()
+        .filter_map(|(_, item_id)| item_from_id(item_id))
+        .map(|item| item.max_stack_size() as i32)
+        .min()
+        .unwrap_or(64);
+
+    let mut remaining = step.times;
+    while remaining > 0 {
+        let batch = remaining.min(batch_size);
+        for (slot, item_id) in &step.inputs {
+            place_in_slot(table, station.input_slot(*slot), item_id, batch);
+        }
+        if let Some(button) = step.button {
+            bot.write_packet(ServerboundGamePacket::ContainerButtonClick(
+                ServerboundContainerButtonClickPacket {
+                    container_id: table.id(),
+                    button_id: button,
+                },
+            ));
+        }
+        // the result is filled in by the server
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        // the button index is worked out locally, so make sure the server agrees
+        if step.button.is_some() {
+            let result = table
+                .contents()
+                .and_then(|contents| contents.get(station.result_slot()).cloned())
+                .map(|slot| slot.kind().to_string());
+            if result.as_deref() != Some(step.recipe.output_item.as_str()) {
+                return Err(BotError::Craft(format!(
+                    "the stonecutter made {} instead of {}",
+                    result.unwrap_or_default(),
+                    step.recipe.output_item
+                )));
+            }
+        }
+        table.click(QuickMoveClick::Left {
+            slot: station.result_slot() as u16,
+        });
+        remaining -= batch;
+    }
+    Ok(())
+}
+
+/// Puts up to `count` of an item from the bot's inventory into a container slot one at
+/// a time, never past a full stack. Returns how many the slot gained
+pub fn place_in_slot(container: &ContainerHandle, slot: usize, item_id: &str, count: i32) -> i32 {
+    let in_slot = || {
+        container
+            .menu()
+            .and_then(|menu| menu.slots().get(slot).cloned())
+            .filter(|target| target.kind().to_string() == item_id)
+            .map(|target| target.count() as i32)
+            .unwrap_or(0)
+    };
+    let before = in_slot();
+    let max_stack_size = item_from_id(item_id)
+        .map(|item| item.max_stack_size() as i32)
+        .unwrap_or(64);
+    let count = count.min(max_stack_size - before);
+    let mut placed = 0;
+    while placed < count {
+        let Some(menu) = container.menu() else {
+            break;
+        };
+        let slots = menu.slots();
+        let Some(source) = menu
+            .player_slots_range()
+            .find(|&i| slots[i].kind().to_string() == item_id)
+        else {
+            break;
+        };
+
+        container.click(PickupClick::Left {
+            slot: Some(source as u16),
+        });
+        for _ in 0..(slots[source].count() as i32).min(count - placed) {
+            container.click(PickupClick::Right {
+                slot: Some(slot as u16),
+            });
+            placed += 1;
+        }
+        container.click(PickupClick::Left {
+            slot: Some(source as u16),
+        });
+        // the clicks don't do anything if the slot holds something else
+        if in_slot() == before {
+            break;
+        }
+    }
+    in_slot() - before
+}
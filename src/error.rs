@@ -0,0 +1,45 @@
+use std::num::{ParseFloatError, ParseIntError};
+
+use azalea_core::BlockPos;
+use thiserror::Error;
+
+use crate::jobs::Cancelled;
+
+/// Why a queued command failed, this is what gets reported to whoever queued it
+#[derive(Debug, Error)]
+pub enum BotError {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("couldn't open the container at {0:?}")]
+    ContainerOpen(BlockPos),
+    #[error("{0}")]
+    Parse(String),
+    #[error("couldn't move to {0:?}")]
+    Movement(BlockPos),
+    #[error("the bot's inventory is full, couldn't take {0}")]
+    InventoryFull(String),
+    #[error("not allowed: {0}")]
+    Permission(String),
+    #[error("no {0} configured")]
+    NoStation(String),
+    #[error("crafting failed, {0}")]
+    Craft(String),
+    #[error("packing failed, {0}")]
+    Packing(String),
+    #[error("the bot isn't connected")]
+    Disconnected,
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+}
+
+impl From<ParseIntError> for BotError {
+    fn from(err: ParseIntError) -> Self {
+        BotError::Parse(err.to_string())
+    }
+}
+
+impl From<ParseFloatError> for BotError {
+    fn from(err: ParseFloatError) -> Self {
+        BotError::Parse(err.to_string())
+    }
+}
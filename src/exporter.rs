@@ -0,0 +1,25 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use crate::{
+    config::Exporter,
+    jobs::{enqueue, JobQueue},
+};
+
+/// Periodically queues an `export` as a background command,
+/// which restocks the exporter's container if it's running low
+pub async fn export_watcher(queue: JobQueue, pool: PgPool, exporter: Exporter) {
+    let command = format!("export {}", exporter.name);
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(exporter.interval_secs)).await;
+
+        if queue.has_background(&command) {
+            continue;
+        }
+        if let Err(err) = enqueue(&pool, &queue, "exporter", &command, true).await {
+            println!("Error: {}", err);
+        }
+    }
+}
@@ -2,17 +2,27 @@ use std::net::SocketAddr;
 
 use futures_channel::mpsc::unbounded;
 use futures_util::{future, pin_mut, StreamExt, TryStreamExt};
+use sqlx::PgPool;
 use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
 
-use crate::{minecraft_handle::WebsocketQueue, PeerMap};
+use crate::{
+    alerts::AlertSubscribers,
+    jobs::{enqueue, EnqueueError, JobQueue},
+    reports::run_report,
+    PeerMap,
+};
 
 pub async fn handle_connection0(
     peer_map: PeerMap,
     stream: TcpStream,
     addr: SocketAddr,
-    queue: WebsocketQueue,
+    queue: JobQueue,
+    pool: PgPool,
+    bot: azalea::Client,
+    subscribers: AlertSubscribers,
 ) {
-    handle_connection(peer_map, stream, addr, queue)
+    handle_connection(peer_map, stream, addr, queue, pool, bot, subscribers)
         .await
         .unwrap();
 }
@@ -21,7 +31,10 @@ pub async fn handle_connection(
     peer_map: PeerMap,
     stream: TcpStream,
     addr: SocketAddr,
-    queue: WebsocketQueue,
+    queue: JobQueue,
+    pool: PgPool,
+    bot: azalea::Client,
+    subscribers: AlertSubscribers,
 ) -> Result<(), Box<dyn std::error::Error>> {
     println!("Incoming TCP connection from: {}", addr);
 
@@ -31,24 +44,71 @@ pub async fn handle_connection(
     println!("WebSocket connection established: {}", addr);
 
     let (tx, rx) = unbounded();
-    peer_map.lock().insert(addr, tx);
+    peer_map.lock().insert(addr, tx.clone());
 
     let (outgoing, incoming) = ws_stream.split();
 
     let broadcast_incoming = incoming.try_for_each(|msg| {
-        let text = msg.to_text().unwrap();
+        let queue = queue.clone();
+        let pool = pool.clone();
+        let bot = bot.clone();
+        let subscribers = subscribers.clone();
+        let tx = tx.clone();
+        async move {
+            let text = msg.to_text().unwrap();
 
-        if text == "PING" || text == "" {
-            return future::ok(());
-        }
+            if text == "PING" || text == "" {
+                return Ok(());
+            }
 
-        println!("Received a message from {}: {}", addr, text);
-        queue
-            .queue
-            .lock()
-            .push_back(msg.to_text().unwrap_or("BAD_MESSAGE").to_string());
+            println!("Received a message from {}: {}", addr, text);
+            let requester = format!("ws:{}", addr);
+            match text {
+                "alert subscribe" => {
+                    subscribers.subscribe(addr);
+                    tx.unbounded_send(Message::Text("Subscribed to alerts".to_string()))
+                        .ok();
+                    return Ok(());
+                }
+                "alert unsubscribe" => {
+                    subscribers.unsubscribe(addr);
+                    tx.unbounded_send(Message::Text("Unsubscribed from alerts".to_string()))
+                        .ok();
+                    return Ok(());
+                }
+                _ => {}
+            }
+            let command_arr = text.split(' ').collect::<Vec<_>>();
+            match run_report(&pool, &queue, &bot, &requester, &command_arr).await {
+                Ok(Some(lines)) => {
+                    for line in lines {
+                        tx.unbounded_send(Message::Text(line)).ok();
+                    }
+                }
+                Ok(None) => match enqueue(&pool, &queue, &requester, text, false).await {
+                    Ok(job_id) => {
+                        tx.unbounded_send(Message::Text(format!("Queued job #{}", job_id)))
+                            .ok();
+                    }
+                    Err(EnqueueError::Database(err)) => {
+                        println!("Error: {}", err);
+                        tx.unbounded_send(Message::Text("Failed to queue command".to_string()))
+                            .ok();
+                    }
+                    Err(err) => {
+                        tx.unbounded_send(Message::Text(format!("Refused: {}", err)))
+                            .ok();
+                    }
+                },
+                Err(err) => {
+                    println!("Error: {}", err);
+                    tx.unbounded_send(Message::Text("Failed to run report".to_string()))
+                        .ok();
+                }
+            }
 
-        future::ok(())
+            Ok(())
+        }
     });
 
     let receive_from_others = rx.map(Ok).forward(outgoing);
@@ -58,6 +118,7 @@ pub async fn handle_connection(
 
     println!("{} disconnected", &addr);
     peer_map.lock().remove(&addr);
+    subscribers.unsubscribe(addr);
 
     Ok(())
 }
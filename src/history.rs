@@ -0,0 +1,142 @@
+use std::time::Duration;
+
+use sqlx::{postgres::PgRow, PgPool, Row};
+
+use crate::{
+    config::CONFIG,
+    jobs::format_duration,
+    postgres::{daily_stock, item_total, prune_history, stock_trend, take_stock_snapshot},
+};
+
+/// Periodically deletes storage history older than the configured retention,
+/// a retention of 0 days keeps everything
+pub async fn history_pruner(pool: PgPool) {
+    loop {
+        if CONFIG.history.retention_days > 0 {
+            match prune_history(&pool, CONFIG.history.retention_days).await {
+                Ok(0) => {}
+                Ok(pruned) => println!("Pruned {} old history row(s)", pruned),
+                Err(err) => println!("Error: {}", err),
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(CONFIG.history.prune_interval_secs)).await;
+    }
+}
+
+/// Periodically saves every item's total so `trend` has something to look at
+pub async fn stock_snapshotter(pool: PgPool) {
+    loop {
+        if let Err(err) = take_stock_snapshot(&pool).await {
+            println!("Error: {}", err);
+        }
+        tokio::time::sleep(Duration::from_secs(CONFIG.history.snapshot_interval_secs)).await;
+    }
+}
+
+/// Lines for the `trend` command: the change over the period, the rate it's being used
+/// at and when it runs out at that rate, then the total at the end of each day
+pub async fn describe_trend(
+    pool: &PgPool,
+    item_id: &str,
+    days: i32,
+) -> Result<Vec<String>, sqlx::Error> {
+    let Some(trend) = stock_trend(pool, item_id, days).await? else {
+        return Ok(vec![format!(
+            "No stock snapshots of {} in the last {} day(s)",
+            item_id, days
+        )]);
+    };
+    let first = trend.get::<i64, _>("first_total");
+    let last = trend.get::<i64, _>("last_total");
+    let mut summary = format!(
+        "{} over {} day(s): {} -> {} ({:+})",
+        item_id,
+        days,
+        first,
+        last,
+        last - first
+    );
+    // a single snapshot has no slope
+    match trend.get::<Option<f64>, _>("per_day") {
+        Some(per_day) if per_day < 0.0 => {
+            let current = item_total(pool, item_id)
+                .await?
+                .map(|row| row.get::<i64, _>("total"))
+                .unwrap_or(0);
+            let days_left = current as f64 / -per_day;
+            summary.push_str(&format!(
+                ", using about {:.0}/day, runs out in {}",
+                -per_day,
+                format_duration(
+                    Duration::try_from_secs_f64(days_left * 86400.0).unwrap_or(Duration::MAX)
+                )
+            ));
+        }
+        Some(per_day) => summary.push_str(&format!(", gaining about {:.0}/day", per_day)),
+        None => {}
+    }
+
+    let mut lines = vec![summary];
+    for row in daily_stock(pool, item_id, days).await? {
+        lines.push(format!(
+            "{}: {}",
+            row.get::<String, _>("day"),
+            row.get::<i64, _>("total")
+        ));
+    }
+    Ok(lines)
+}
+
+/// Lines for a `chest_history` row, like
+/// `2m 5s ago: -3 minecraft:diamond at (1, 2, 3) slot 4, withdraw #12 by Steve`,
+/// optionally only for one item
+pub fn describe_change(row: &PgRow, only_item: Option<&str>) -> Vec<String> {
+    let old_item = row.get::<Option<String>, _>("old_item_id");
+    let new_item = row.get::<Option<String>, _>("new_item_id");
+    let old_count = row.get::<Option<i16>, _>("old_item_count").unwrap_or(0) as i32;
+    let new_count = row.get::<Option<i16>, _>("new_item_count").unwrap_or(0) as i32;
+
+    // a slot that switched items is a removal of one and an addition of the other
+    let mut deltas = vec![];
+    if old_item == new_item {
+        if let Some(item_id) = old_item {
+            deltas.push((item_id, new_count - old_count));
+        }
+    } else {
+        if let Some(item_id) = old_item.filter(|_| old_count > 0) {
+            deltas.push((item_id, -old_count));
+        }
+        if let Some(item_id) = new_item.filter(|_| new_count > 0) {
+            deltas.push((item_id, new_count));
+        }
+    }
+
+    let mut source = row.get::<String, _>("cause");
+    if let Some(job_id) = row.get::<Option<i64>, _>("job_id") {
+        source.push_str(&format!(" #{}", job_id));
+    }
+    if let Some(requester) = row.get::<Option<String>, _>("requester") {
+        source.push_str(&format!(" by {}", requester));
+    }
+    let age = format_duration(Duration::from_secs(
+        row.get::<i64, _>("age_secs").max(0) as u64
+    ));
+
+    deltas
+        .into_iter()
+        .filter(|(item_id, delta)| *delta != 0 && only_item.map_or(true, |only| only == item_id))
+        .map(|(item_id, delta)| {
+            format!(
+                "{} ago: {:+} {} at ({}, {}, {}) slot {}, {}",
+                age,
+                delta,
+                item_id,
+                row.get::<f64, _>("x"),
+                row.get::<f64, _>("y"),
+                row.get::<f64, _>("z"),
+                row.get::<i32, _>("location_in_chest"),
+                source
+            )
+        })
+        .collect()
+}
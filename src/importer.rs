@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use sqlx::PgPool;
+
+use crate::{
+    config::Depot,
+    jobs::{enqueue, JobQueue},
+};
+
+/// Periodically queues an `import` of the depot as a background command,
+/// like a Refined Storage importer pulling from the input depot
+pub async fn import_watcher(queue: JobQueue, pool: PgPool, depot: Depot) {
+    let importer = match &depot.importer {
+        Some(importer) => importer.clone(),
+        None => return,
+    };
+    let command = format!("import {}", depot.name);
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(importer.interval_secs)).await;
+
+        if importer.idle_only && !queue.is_idle() {
+            continue;
+        }
+
+        // don't pile up imports if the queue thread is falling behind
+        if queue.has_background(&command) {
+            continue;
+        }
+        if let Err(err) = enqueue(&pool, &queue, "importer", &command, true).await {
+            println!("Error: {}", err);
+        }
+    }
+}
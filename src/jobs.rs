@@ -0,0 +1,587 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use azalea::prelude::*;
+use parking_lot::Mutex;
+use sqlx::{PgPool, Row};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+use crate::{
+    config::CONFIG,
+    postgres::{
+        clear_reservations, get_job, insert_job, release_reservations, requeue_interrupted_jobs,
+        set_job_finished, set_job_running, unfinished_jobs,
+    },
+};
+
+/// How many times a job is restarted after the bot went down while running it
+const MAX_ATTEMPTS: i32 = 3;
+/// Waiting jobs listed by the `queue` command, so it doesn't flood the chat
+const MAX_LISTED: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub job_id: i64,
+    pub command: String,
+    pub requester: String,
+    pub priority: Priority,
+    /// When the job was queued, or resumed after a restart
+    pub queued_at: Instant,
+}
+
+/// How far a long job has got, for the `queue` and `status` commands
+#[derive(Debug, Clone)]
+struct Progress {
+    done: usize,
+    total: usize,
+    unit: &'static str,
+    started: Instant,
+}
+
+impl Progress {
+    /// Assumes the rest goes as fast as what's been done so far
+    fn eta(&self) -> Option<Duration> {
+        if self.done == 0 {
+            return None;
+        }
+        let per_unit = self.started.elapsed() / self.done as u32;
+        Some(per_unit * self.total.saturating_sub(self.done) as u32)
+    }
+}
+
+tokio::task_local! {
+    /// Job whose command is running on this task, so changes to storage can be put down to it
+    static RUNNING_JOB: Job;
+}
+
+/// Runs a job's command with changes to storage attributed to the job
+pub async fn attribute_to<F: Future>(job: Job, command: F) -> F::Output {
+    RUNNING_JOB.scope(job, command).await
+}
+
+/// What a change to storage gets put down to in its history
+#[derive(Debug, Clone)]
+pub struct Audit {
+    pub cause: String,
+    pub job_id: Option<i64>,
+    pub requester: Option<String>,
+}
+
+impl Audit {
+    /// The job running on this task, or `manual` for changes made outside the queue
+    pub fn current() -> Self {
+        RUNNING_JOB
+            .try_with(|job| Audit {
+                cause: job
+                    .command
+                    .split(' ')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+                job_id: Some(job.job_id),
+                requester: Some(job.requester.clone()),
+            })
+            .unwrap_or_else(|_| Audit {
+                cause: "manual".to_string(),
+                job_id: None,
+                requester: None,
+            })
+    }
+}
+
+/// Jobs with a higher priority run first, and can interrupt long jobs between barrels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Background,
+    Normal,
+    Urgent,
+}
+
+impl Priority {
+    /// Highest first
+    const ALL: [Priority; 3] = [Priority::Urgent, Priority::Normal, Priority::Background];
+
+    /// Withdrawing goes before depositing, which goes before anything that walks the whole storage
+    pub fn of(command: &str, background: bool) -> Self {
+        if background {
+            return Priority::Background;
+        }
+        match command.split(' ').next().unwrap_or_default() {
+            "withdraw" | "find" | "viewchest" | "sayhi" => Priority::Urgent,
+            "index" | "compact" | "cleardb" | "loadrecipes" => Priority::Background,
+            _ => Priority::Normal,
+        }
+    }
+
+    /// Value used for the `priority` column of jobs
+    pub fn as_i16(&self) -> i16 {
+        *self as i16
+    }
+
+    pub fn from_i16(value: i16) -> Self {
+        match value {
+            0 => Priority::Background,
+            2 => Priority::Urgent,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Name used for the `status` column of jobs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum EnqueueError {
+    #[error("the queue is full, {0} job(s) are already waiting")]
+    QueueFull(usize),
+    #[error("{0} already has {1} job(s) waiting")]
+    TooManyForRequester(String, usize),
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+/// Returned by a job that stopped at a safe point because it was cancelled,
+/// with what it got done before that
+#[derive(Debug, Error)]
+#[error("cancelled, {0}")]
+pub struct Cancelled(pub String);
+
+/// Jobs of one priority, each requester gets a turn before anyone goes twice
+#[derive(Default)]
+struct Level {
+    requesters: VecDeque<String>,
+    jobs: HashMap<String, VecDeque<Job>>,
+}
+
+impl Level {
+    fn push(&mut self, job: Job) {
+        let jobs = self.jobs.entry(job.requester.clone()).or_default();
+        if jobs.is_empty() {
+            self.requesters.push_back(job.requester.clone());
+        }
+        jobs.push_back(job);
+    }
+
+    fn pop(&mut self) -> Option<Job> {
+        let requester = self.requesters.pop_front()?;
+        let jobs = self.jobs.get_mut(&requester)?;
+        let job = jobs.pop_front();
+        if jobs.is_empty() {
+            self.jobs.remove(&requester);
+        } else {
+            self.requesters.push_back(requester);
+        }
+        job
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.values().flatten()
+    }
+
+    fn remove(&mut self, job_id: i64) -> Option<Job> {
+        let (requester, jobs) = self
+            .jobs
+            .iter_mut()
+            .find(|(_, jobs)| jobs.iter().any(|job| job.job_id == job_id))?;
+        let requester = requester.clone();
+        let index = jobs.iter().position(|job| job.job_id == job_id)?;
+        let job = jobs.remove(index);
+        if jobs.is_empty() {
+            self.jobs.remove(&requester);
+            self.requesters.retain(|name| *name != requester);
+        }
+        job
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    /// Indexed by `Priority`
+    levels: [Level; 3],
+}
+
+impl Pending {
+    fn level(&mut self, priority: Priority) -> &mut Level {
+        &mut self.levels[priority as usize]
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Job> {
+        self.levels.iter().flat_map(Level::iter)
+    }
+
+    /// Takes the next job that has a higher priority than `above`, if any
+    fn pop_above(&mut self, above: Option<Priority>) -> Option<Job> {
+        Priority::ALL
+            .into_iter()
+            .filter(|priority| Some(*priority) > above)
+            .find_map(|priority| self.level(priority).pop())
+    }
+}
+
+/// Jobs waiting for the queue thread, which gets woken up as soon as one is pushed
+#[derive(Default, Clone, Component)]
+pub struct JobQueue {
+    pending: Arc<Mutex<Pending>>,
+    notify: Arc<Notify>,
+    /// Job the queue thread is running right now
+    pub current: Arc<Mutex<Option<Job>>>,
+    /// Running jobs that should stop at their next safe point
+    cancelled: Arc<Mutex<HashSet<i64>>>,
+    paused: Arc<Mutex<bool>>,
+    /// Wakes jobs waiting at a safe point when the queue is resumed or they're cancelled
+    control: Arc<Notify>,
+    progress: Arc<Mutex<HashMap<i64, Progress>>>,
+}
+
+impl JobQueue {
+    /// Queues a job, refusing it if the queue or the requester is over the configured limits
+    pub fn try_push(&self, job: Job) -> Result<(), EnqueueError> {
+        self.check_limits(&job.requester)?;
+        self.push(job);
+        Ok(())
+    }
+
+    fn push(&self, job: Job) {
+        self.pending.lock().level(job.priority).push(job);
+        self.notify.notify_one();
+    }
+
+    fn check_limits(&self, requester: &str) -> Result<(), EnqueueError> {
+        let pending = self.pending.lock();
+        let waiting = pending.iter().count();
+        if waiting >= CONFIG.queue.max_pending {
+            return Err(EnqueueError::QueueFull(waiting));
+        }
+        let from_requester = pending
+            .iter()
+            .filter(|job| job.requester == requester)
+            .count();
+        if from_requester >= CONFIG.queue.max_per_requester {
+            return Err(EnqueueError::TooManyForRequester(
+                requester.to_string(),
+                from_requester,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Waits for the next job with the highest priority, and for the queue to be resumed
+    pub async fn pop(&self) -> Job {
+        loop {
+            if !self.is_paused() {
+                if let Some(job) = self.pending.lock().pop_above(None) {
+                    return job;
+                }
+            }
+            // `notify_one` stores a permit if nobody is waiting yet,
+            // so a push between the check above and here isn't missed
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.lock()
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        *self.paused.lock() = paused;
+        if !paused {
+            self.notify.notify_one();
+            self.control.notify_waiters();
+        }
+    }
+
+    pub fn is_cancelled(&self, job_id: i64) -> bool {
+        self.cancelled.lock().contains(&job_id)
+    }
+
+    /// Forgets the progress and cancellation of a job once it's over
+    pub fn job_finished(&self, job_id: i64) {
+        self.cancelled.lock().remove(&job_id);
+        self.progress.lock().remove(&job_id);
+    }
+
+    /// Records how far a long job has got, like `14` of `52` `barrels`
+    pub fn set_progress(&self, job: &Job, done: usize, total: usize, unit: &'static str) {
+        let mut progress = self.progress.lock();
+        let progress = progress.entry(job.job_id).or_insert(Progress {
+            done,
+            total,
+            unit,
+            started: Instant::now(),
+        });
+        progress.done = done;
+        progress.total = total;
+        progress.unit = unit;
+    }
+
+    /// Lines for the `queue` command, the running job then everything waiting,
+    /// highest priority first
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![];
+        match self.current.lock().as_ref() {
+            Some(job) => lines.push(format!("Running: {}", self.describe_job(job))),
+            None => lines.push("Nothing running".to_string()),
+        }
+        let mut waiting = self.pending.lock().iter().cloned().collect::<Vec<_>>();
+        waiting.sort_by_key(|job| (std::cmp::Reverse(job.priority), job.job_id));
+        for job in waiting.iter().take(MAX_LISTED) {
+            lines.push(format!("Waiting: {}", self.describe_job(job)));
+        }
+        if waiting.len() > MAX_LISTED {
+            lines.push(format!("...and {} more", waiting.len() - MAX_LISTED));
+        }
+        if self.is_paused() {
+            lines.push("The queue is paused".to_string());
+        }
+        lines
+    }
+
+    fn describe_job(&self, job: &Job) -> String {
+        let mut line = format!(
+            "#{} {} for {} ({:?}, queued {} ago)",
+            job.job_id,
+            job.command,
+            job.requester,
+            job.priority,
+            format_duration(job.queued_at.elapsed())
+        );
+        if let Some(progress) = self.progress.lock().get(&job.job_id) {
+            line.push_str(&format!(
+                ", {} {}/{}",
+                progress.unit, progress.done, progress.total
+            ));
+            if let Some(eta) = progress.eta() {
+                line.push_str(&format!(", about {} left", format_duration(eta)));
+            }
+        }
+        line
+    }
+
+    /// One line summary for the `status` command
+    pub fn summary(&self) -> String {
+        let running = match self.current.lock().as_ref() {
+            Some(job) => self.describe_job(job),
+            None => "idle".to_string(),
+        };
+        let waiting = self.pending.lock().iter().count();
+        format!(
+            "{}{}, {} job(s) waiting",
+            running,
+            if self.is_paused() { " (paused)" } else { "" },
+            waiting
+        )
+    }
+
+    /// Called by long jobs where stopping leaves storage and the db consistent.
+    /// Waits while the queue is paused, and stops the job if it was cancelled
+    pub async fn checkpoint(&self, job: &Job, progress: &str) -> Result<(), Cancelled> {
+        loop {
+            // created before checking so a resume in between still wakes it
+            let notified = self.control.notified();
+            if self.is_cancelled(job.job_id) {
+                return Err(Cancelled(progress.to_string()));
+            }
+            if !self.is_paused() {
+                return Ok(());
+            }
+            notified.await;
+        }
+    }
+
+    /// Whether a waiting job should interrupt a running job of `priority`
+    pub fn has_waiting_above(&self, priority: Priority) -> bool {
+        let pending = self.pending.lock();
+        Priority::ALL
+            .into_iter()
+            .filter(|level| *level > priority)
+            .any(|level| !pending.levels[level as usize].requesters.is_empty())
+    }
+
+    /// Takes a waiting job that should interrupt a running job of `priority`
+    pub fn try_pop_above(&self, priority: Priority) -> Option<Job> {
+        self.pending.lock().pop_above(Some(priority))
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.current.lock().is_none() && self.pending.lock().iter().next().is_none()
+    }
+
+    pub fn has_background(&self, command: &str) -> bool {
+        self.pending
+            .lock()
+            .level(Priority::Background)
+            .iter()
+            .any(|job| job.command == command)
+    }
+}
+
+/// Saves a command as a job and queues it, returns the job id.
+/// Background jobs only run while there are no other jobs waiting
+pub async fn enqueue(
+    pool: &PgPool,
+    queue: &JobQueue,
+    requester: &str,
+    command: &str,
+    background: bool,
+) -> Result<i64, EnqueueError> {
+    // check before saving so refused jobs don't end up in the db
+    queue.check_limits(requester)?;
+    let priority = Priority::of(command, background);
+    let job_id = insert_job(
+        pool,
+        requester,
+        command,
+        priority.as_i16(),
+        JobStatus::Queued.as_str(),
+    )
+    .await?;
+    let job = Job {
+        job_id,
+        command: command.to_string(),
+        requester: requester.to_string(),
+        priority,
+        queued_at: Instant::now(),
+    };
+    if let Err(err) = queue.try_push(job) {
+        set_job_finished(
+            pool,
+            job_id,
+            JobStatus::Failed.as_str(),
+            Some(&err.to_string()),
+        )
+        .await?;
+        return Err(err);
+    }
+    Ok(job_id)
+}
+
+/// Puts every job that hadn't finished when the bot last stopped back into the queue,
+/// returns how many were resumed
+pub async fn resume_jobs(pool: &PgPool, queue: &JobQueue) -> Result<usize, sqlx::Error> {
+    requeue_interrupted_jobs(pool, MAX_ATTEMPTS).await?;
+    clear_reservations(pool).await?;
+    let rows = unfinished_jobs(pool).await?;
+    let resumed = rows.len();
+    for row in rows {
+        let job = Job {
+            job_id: row.get("job_id"),
+            command: row.get("command"),
+            requester: row.get("requester"),
+            priority: Priority::from_i16(row.get("priority")),
+            queued_at: Instant::now(),
+        };
+        // these were accepted before, so they skip the limits
+        queue.push(job);
+    }
+    Ok(resumed)
+}
+
+/// Handles `cancel <job>`, `pause` and `resume`, which take effect right away
+/// instead of waiting in the queue
+pub async fn run_control(
+    pool: &PgPool,
+    queue: &JobQueue,
+    command_arr: &[&str],
+) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let line = match command_arr[0] {
+        "pause" => {
+            queue.set_paused(true);
+            "Paused, the running job stops at its next safe point".to_string()
+        }
+        "resume" => {
+            queue.set_paused(false);
+            "Resumed".to_string()
+        }
+        "cancel" => {
+            let Some(Ok(job_id)) = command_arr
+                .get(1)
+                .map(|id| id.trim_start_matches('#').parse::<i64>())
+            else {
+                return Ok(Some(vec!["Usage: cancel <job>".to_string()]));
+            };
+            let waiting = queue
+                .pending
+                .lock()
+                .levels
+                .iter_mut()
+                .find_map(|level| level.remove(job_id));
+            if waiting.is_some() {
+                finish_job(
+                    pool,
+                    job_id,
+                    JobStatus::Cancelled,
+                    Some("cancelled before it started"),
+                )
+                .await?;
+                format!("Cancelled job #{}", job_id)
+            } else {
+                match get_job(pool, job_id).await? {
+                    Some(row) if row.get::<&str, _>("status") == JobStatus::Running.as_str() => {
+                        queue.cancelled.lock().insert(job_id);
+                        queue.control.notify_waiters();
+                        format!("Cancelling job #{} at its next safe point", job_id)
+                    }
+                    Some(row) => format!(
+                        "Job #{} is already {}",
+                        job_id,
+                        row.get::<&str, _>("status")
+                    ),
+                    None => format!("No job #{}", job_id),
+                }
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(vec![line]))
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+pub async fn start_job(pool: &PgPool, job_id: i64) -> Result<(), sqlx::Error> {
+    set_job_running(pool, job_id).await
+}
+
+pub async fn finish_job(
+    pool: &PgPool,
+    job_id: i64,
+    status: JobStatus,
+    result: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    set_job_finished(pool, job_id, status.as_str(), result).await?;
+    // whatever the job didn't get to take is free for the next one
+    release_reservations(pool, job_id).await?;
+    Ok(())
+}
@@ -8,12 +8,24 @@ use futures_channel::mpsc::UnboundedSender;
 use parking_lot::{deadlock, Mutex};
 use tokio_tungstenite::tungstenite::Message;
 
+mod alerts;
 mod bot_handle_queue;
+mod compact;
 mod config;
+mod crafting;
+mod error;
+mod exporter;
 mod find_blocks;
 mod handle_websockets;
+mod history;
+mod importer;
+mod jobs;
 mod minecraft_handle;
+mod packing;
 mod postgres;
+mod recipes;
+mod reports;
+mod smelting;
 
 type Tx = UnboundedSender<Message>;
 type PeerMap = Arc<Mutex<HashMap<SocketAddr, Tx>>>;
@@ -1,4 +1,3 @@
-use std::collections::LinkedList;
 use std::{collections::HashMap, sync::Arc};
 
 use azalea::container::ContainerHandle;
@@ -11,10 +10,16 @@ use sqlx::PgPool;
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
 use tokio::net::TcpListener;
 
-use crate::config::CONFIG;
+use crate::alerts::{alert_watcher, AlertSubscribers};
+use crate::bot_handle_queue::save_contents;
+use crate::config::{DepotKind, CONFIG};
+use crate::exporter::export_watcher;
 use crate::find_blocks::find_blocks;
 use crate::handle_websockets::handle_connection0;
-use crate::postgres::{create_chest, set_item_in_chest};
+use crate::history::{history_pruner, stock_snapshotter};
+use crate::importer::import_watcher;
+use crate::jobs::{enqueue, resume_jobs, EnqueueError, JobQueue};
+use crate::reports::run_report;
 use crate::{bot_handle_queue, PeerMap};
 
 #[derive(Default, Clone, Component)]
@@ -32,10 +37,7 @@ pub async fn minecraft_handle(
         Event::Init => {
             let init_lock = state.init_lock.lock().await;
 
-            let queue = Arc::new(Mutex::new(LinkedList::new()));
-            let queue = WebsocketQueue {
-                queue: Arc::clone(&queue),
-            };
+            let queue = JobQueue::default();
 
             bot.ecs.lock().entity_mut(bot.entity).insert(queue.clone());
 
@@ -52,14 +54,27 @@ pub async fn minecraft_handle(
                 .entity_mut(bot.entity)
                 .insert(PostgresComponent { pool: pool.clone() });
 
+            let resumed = resume_jobs(&pool, &queue).await?;
+            if resumed > 0 {
+                println!("Resumed {} unfinished job(s)", resumed);
+            }
+
             let addr = &CONFIG.connections.websocket_host;
             let state = PeerMap::new(Mutex::new(HashMap::new()));
             let listener = TcpListener::bind(addr)
                 .await
                 .expect("Unable to bind, is the port already in use?");
+            let peers = state.clone();
+            let subscribers = AlertSubscribers::default();
             let queue2 = queue.clone();
+            let pool2 = pool.clone();
+            let bot2 = bot.clone();
+            let subscribers2 = subscribers.clone();
             tokio::spawn(async move {
                 let queue = queue2;
+                let pool = pool2;
+                let bot = bot2;
+                let subscribers = subscribers2;
                 loop {
                     let (stream, addr) = listener.accept().await.unwrap();
                     tokio::spawn(handle_connection0(
@@ -67,13 +82,34 @@ pub async fn minecraft_handle(
                         stream,
                         addr,
                         queue.clone(),
+                        pool.clone(),
+                        bot.clone(),
+                        subscribers.clone(),
                     ));
                 }
             });
+            for depot in &CONFIG.depots {
+                if depot.kind == DepotKind::Input && depot.importer.is_some() {
+                    tokio::spawn(import_watcher(queue.clone(), pool.clone(), depot.clone()));
+                }
+            }
+            tokio::spawn(history_pruner(pool.clone()));
+            tokio::spawn(stock_snapshotter(pool.clone()));
+            tokio::spawn(alert_watcher(
+                pool.clone(),
+                bot.clone(),
+                peers.clone(),
+                subscribers,
+            ));
+            for exporter in &CONFIG.exporters {
+                tokio::spawn(export_watcher(
+                    queue.clone(),
+                    pool.clone(),
+                    exporter.clone(),
+                ));
+            }
             tokio::spawn(async move {
-                bot_handle_queue::bot_handle_queue(queue.clone(), bot, pool)
-                    .await
-                    .unwrap();
+                bot_handle_queue::bot_handle_queue(queue.clone(), bot, pool, peers).await;
             });
 
             drop(init_lock);
@@ -85,7 +121,7 @@ pub async fn minecraft_handle(
     drop(state.init_lock.lock().await);
 
     let pool: PgPool = bot.component::<PostgresComponent>().pool;
-    let queue: Arc<Mutex<LinkedList<String>>> = bot.component::<WebsocketQueue>().queue;
+    let queue: JobQueue = bot.component::<JobQueue>();
 
     match event {
         Event::Chat(m) => {
@@ -100,7 +136,30 @@ pub async fn minecraft_handle(
                     let content = m.content();
                     let mut command = content.chars();
                     command.next();
-                    queue.lock().push_back(command.as_str().to_string());
+                    let command = command.as_str().to_string();
+                    let command_arr = command.split(' ').collect::<Vec<_>>();
+                    let requester = m.username().unwrap_or_default();
+                    match run_report(&pool, &queue, &bot, &requester, &command_arr).await {
+                        Ok(Some(lines)) => {
+                            for line in lines {
+                                bot.chat(&line);
+                            }
+                        }
+                        Ok(None) => {
+                            match enqueue(&pool, &queue, &requester, &command, false).await {
+                                Ok(job_id) => bot.chat(&format!("Queued job #{}", job_id)),
+                                Err(EnqueueError::Database(err)) => {
+                                    println!("Error: {}", err);
+                                    bot.chat("Failed to queue command, check logs");
+                                }
+                                Err(err) => bot.chat(&format!("Refused: {}", err)),
+                            }
+                        }
+                        Err(err) => {
+                            println!("Error: {}", err);
+                            bot.chat("Failed to run report, check logs");
+                        }
+                    }
                 }
             }
 
@@ -135,34 +194,10 @@ pub async fn minecraft_handle(
                     None => continue,
                 };
 
-                create_chest(
-                    &pool,
-                    chest_block.x as f64,
-                    chest_block.y as f64,
-                    chest_block.z as f64,
-                )
-                .await?;
-
                 println!("Getting contents");
-                for (index, slot) in chest
-                    .contents()
-                    .expect("we just opened the chest")
-                    .iter()
-                    .enumerate()
-                {
-                    set_item_in_chest(
-                        &pool,
-                        chest_block.x as f64,
-                        chest_block.y as f64,
-                        chest_block.z as f64,
-                        index.try_into().unwrap_or(-1),
-                        &slot.kind().to_string(),
-                        slot.count() as i16,
-                        None,
-                    )
-                    .await
-                    .unwrap();
-
+                let contents = chest.contents().expect("we just opened the chest");
+                save_contents(&pool, chest_block, &contents).await?;
+                for (index, slot) in contents.iter().enumerate() {
                     println!("Checking slot {index}: {slot:?}");
                     if let ItemSlot::Present(item) = slot {
                         bot.chat(&format!("found item: [{} x{}]", item.kind, item.count));
@@ -189,8 +224,3 @@ pub async fn minecraft_handle(
 struct PostgresComponent {
     pool: Pool<Postgres>,
 }
-
-#[derive(Clone, Component)]
-pub struct WebsocketQueue {
-    pub queue: Arc<Mutex<LinkedList<String>>>,
-}
@@ -0,0 +1,408 @@
+use std::time::Duration;
+
+use azalea::container::ContainerHandle;
+use azalea::protocol::packets::game::{
+    serverbound_set_carried_item_packet::ServerboundSetCarriedItemPacket, ServerboundGamePacket,
+};
+use azalea::{BlockPos, Vec3};
+use azalea_inventory::item::MaxStackSizeExt;
+use azalea_inventory::operations::SwapClick;
+use sqlx::PgPool;
+
+use crate::{
+    bot_handle_queue::{
+        count_in_container, get_storage_handle, inventory_item_ids, put_into_container, quick_move,
+        record_inventory, store_inventory, take_from_storage, teleport_to,
+    },
+    config::{Depot, Packing, Region},
+    error::BotError,
+    jobs::Audit,
+    postgres::{forget_packed, insert_packed_shulker},
+    reports::item_from_id,
+};
+
+/// Only undyed boxes get packed. Storage only ever holds empty ones,
+/// packed boxes go straight to a depot and the importer shouldn't take them back
+pub const SHULKER_BOX: &str = "minecraft:shulker_box";
+/// Where a packed box is recorded when no output depot had room for it
+pub const SHULKER_BARREL: &str = "shulker barrel";
+pub const PACKING_STATION: &str = "packing station";
+const SHULKER_SLOTS: i32 = 27;
+/// How long a broken box gets to drop and be picked up
+const PICKUP_MILLIS: u64 = 500;
+
+/// Whether a withdraw of `count` is big enough to get packed without asking
+pub fn should_pack(packing: &Packing, item_id: &str, count: i32) -> bool {
+    packing
+        .min_stacks
+        .is_some_and(|min_stacks| count >= min_stacks * stack_size(item_id))
+}
+
+fn stack_size(item_id: &str) -> i32 {
+    item_from_id(item_id)
+        .map(|item| item.max_stack_size() as i32)
+        .unwrap_or(64)
+}
+
+/// Withdraws `count` of an item packed into shulker boxes, taking one empty box
+/// from storage at a time and delivering each one as soon as it's full.
+/// `depots` are tried in order for each box. Returns how many ended up packed
+#[allow(clippy::too_many_arguments)]
+pub async fn pack_withdraw(
+    bot: &mut azalea::Client,
+    pool: &PgPool,
+    region: &Region,
+    packing: &Packing,
+    depots: &[&Depot],
+    item_id: &str,
+    count: i32,
+    stop: impl Fn() -> bool,
+) -> Result<i32, BotError> {
+    let per_box = SHULKER_SLOTS * stack_size(item_id);
+    let mut packed = 0;
+    while packed < count && !stop() {
+        // a packed box can't go back into storage, so only pack one there's room for
+        let Some(depot) = depot_with_room(bot, pool, depots).await? else {
+            bot.chat("Every output depot is full");
+            break;
+        };
+        if !holding(bot, SHULKER_BOX)
+            && take_from_storage(bot, pool, region, SHULKER_BOX, Some(1), || false).await? == 0
+        {
+            bot.chat("Out of empty shulker boxes");
+            break;
+        }
+        // whole stacks get withdrawn, so there can be some left over from the last box
+        let wanted = (count - packed).min(per_box) - held_count(bot, item_id);
+        if wanted > 0 {
+            take_from_storage(bot, pool, region, item_id, Some(wanted), &stop).await?;
+        }
+        if held_count(bot, item_id) == 0 {
+            break;
+        }
+
+        let in_box = pack_shulker(bot, pool, packing, item_id).await?;
+        let left_at = deliver_box(bot, pool, packing, depot).await?;
+        insert_packed_shulker(pool, item_id, in_box, &left_at, &Audit::current()).await?;
+        packed += in_box;
+        bot.chat(&format!(
+            "Packed {}x {} into a shulker box at {}",
+            in_box, item_id, left_at
+        ));
+    }
+    Ok(packed)
+}
+
+/// The first depot with an empty slot. Forgets the packed boxes players have taken
+/// from the depots it looks in on the way
+async fn depot_with_room<'a>(
+    bot: &mut azalea::Client,
+    pool: &PgPool,
+    depots: &[&'a Depot],
+) -> Result<Option<&'a Depot>, BotError> {
+    for depot in depots {
+        teleport_to(bot, depot.x, depot.y, depot.z)?;
+        let blockpos = depot.storage_pos();
+        let barrel = get_storage_handle(bot, blockpos)
+            .await
+            .ok_or(BotError::ContainerOpen(blockpos))?;
+        let contents = barrel.contents().ok_or(BotError::ContainerOpen(blockpos))?;
+        let boxes = contents
+            .iter()
+            .filter(|slot| slot.kind().to_string() == SHULKER_BOX)
+            .count();
+        forget_packed(pool, &depot.name, boxes as i64).await?;
+        if contents.iter().any(|slot| slot.count() == 0) {
+            return Ok(Some(*depot));
+        }
+    }
+    Ok(None)
+}
+
+/// Puts the packed box into the depot. If a player filled the depot up in the
+/// meantime it goes into the shulker barrel, or is left standing at the packing
+/// station, so it never ends up in storage looking like an empty box.
+/// Returns where it was left
+async fn deliver_box(
+    bot: &mut azalea::Client,
+    pool: &PgPool,
+    packing: &Packing,
+    depot: &Depot,
+) -> Result<String, BotError> {
+    teleport_to(bot, depot.x, depot.y, depot.z)?;
+    put_into_container(bot, depot.storage_pos(), |item| {
+        item.kind.to_string() == SHULKER_BOX
+    })
+    .await?;
+    record_inventory(bot, pool).await?;
+    if !holding(bot, SHULKER_BOX) {
+        return Ok(depot.name.clone());
+    }
+
+    if let Some(shulker_barrel) = &packing.shulker_barrel {
+        teleport_to(bot, shulker_barrel.x, shulker_barrel.y, shulker_barrel.z)?;
+        // counts the empty boxes in there too
+        let boxes = count_in_container(bot, shulker_barrel.block_pos(), SHULKER_BOX).await?;
+        forget_packed(pool, SHULKER_BARREL, boxes as i64).await?;
+        put_into_container(bot, shulker_barrel.block_pos(), |item| {
+            item.kind.to_string() == SHULKER_BOX
+        })
+        .await?;
+        record_inventory(bot, pool).await?;
+        if !holding(bot, SHULKER_BOX) {
+            return Ok(SHULKER_BARREL.to_string());
+        }
+    }
+
+    drop(place_box(bot, packing, SHULKER_BOX).await?);
+    record_inventory(bot, pool).await?;
+    Ok(PACKING_STATION.to_string())
+}
+
+/// Places the box the bot is holding at the packing station, fills it with the item
+/// and breaks it again. Returns how many went in
+async fn pack_shulker(
+    bot: &mut azalea::Client,
+    pool: &PgPool,
+    packing: &Packing,
+    item_id: &str,
+) -> Result<i32, BotError> {
+    let pos = packing.block_pos();
+    let shulker = place_box(bot, packing, SHULKER_BOX).await?;
+    // a packed box that couldn't be delivered is left standing here
+    if shulker
+        .contents()
+        .ok_or(BotError::ContainerOpen(pos))?
+        .iter()
+        .any(|slot| slot.count() > 0)
+    {
+        return Err(BotError::Packing(
+            "there's a packed box at the packing station, it has to be taken away first"
+                .to_string(),
+        ));
+    }
+    // the box was placed, so any packed one left standing here has been taken
+    forget_packed(pool, PACKING_STATION, 0).await?;
+    let menu = shulker.menu().ok_or(BotError::ContainerOpen(pos))?;
+    let slots = menu.slots();
+    for slot in menu.player_slots_range() {
+        if slots[slot].kind().to_string() == item_id {
+            quick_move(&shulker, slot).await;
+        }
+    }
+    // count what's in the box rather than what the clicks should have moved
+    let in_box = shulker
+        .contents()
+        .ok_or(BotError::ContainerOpen(pos))?
+        .iter()
+        .filter(|slot| slot.kind().to_string() == item_id)
+        .map(|slot| slot.count() as i32)
+        .sum();
+    drop(shulker);
+
+    break_box(bot, pool, packing, SHULKER_BOX).await?;
+    Ok(in_box)
+}
+
+pub fn is_shulker_box(item_id: &str) -> bool {
+    item_id.ends_with("shulker_box")
+}
+
+/// Unpacks the shulker boxes in an input depot one at a time, since a full box needs
+/// most of the inventory, and stores what was in them. Only boxes `accept` allows are
+/// unpacked, and one holding anything it doesn't allow goes back into the depot as it is. Emptied boxes are always broken,
+/// one left standing would block the packing station for the next box and for packed
+/// withdraws. They go to the shulker barrel if there is one, otherwise they're stored
+/// like anything else. Returns how many boxes were unpacked
+pub async fn unpack_depot(
+    bot: &mut azalea::Client,
+    pool: &PgPool,
+    region: &Region,
+    packing: &Packing,
+    depot: &Depot,
+    accept: impl Fn(&str) -> bool,
+) -> Result<usize, BotError> {
+    let mut unpacked = 0;
+    // boxes put back stay in front of the ones that haven't been looked at yet
+    let mut rejected = 0;
+    loop {
+        teleport_to(bot, depot.x, depot.y, depot.z)?;
+        let blockpos = depot.storage_pos();
+        let barrel = get_storage_handle(bot, blockpos)
+            .await
+            .ok_or(BotError::ContainerOpen(blockpos))?;
+        let contents = barrel.contents().ok_or(BotError::ContainerOpen(blockpos))?;
+        let Some(slot) = contents
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| {
+                let item_id = slot.kind().to_string();
+                is_shulker_box(&item_id) && accept(&item_id)
+            })
+            .map(|(index, _)| index)
+            .nth(rejected)
+        else {
+            break;
+        };
+        let box_id = contents[slot].kind().to_string();
+        quick_move(&barrel, slot).await;
+        drop(barrel);
+        if !holding(bot, &box_id) {
+            return Err(BotError::InventoryFull(box_id));
+        }
+
+        let pos = packing.block_pos();
+        let shulker = place_box(bot, packing, &box_id).await?;
+        let contents = shulker.contents().ok_or(BotError::ContainerOpen(pos))?;
+        if let Some(refused) = contents
+            .iter()
+            .filter(|slot| slot.count() > 0)
+            .map(|slot| slot.kind().to_string())
+            .find(|item_id| !accept(item_id))
+        {
+            drop(shulker);
+            break_box(bot, pool, packing, &box_id).await?;
+            teleport_to(bot, depot.x, depot.y, depot.z)?;
+            put_into_container(bot, blockpos, |item| item.kind.to_string() == box_id).await?;
+            record_inventory(bot, pool).await?;
+            bot.chat(&format!(
+                "Left a {} in depot '{}', it has {} in it",
+                box_id, depot.name, refused
+            ));
+            rejected += 1;
+            continue;
+        }
+        for (index, slot) in contents.iter().enumerate() {
+            if slot.count() > 0 {
+                quick_move(&shulker, index).await;
+            }
+        }
+        // a broken box keeps what's in it, so one that isn't empty would end up
+        // stored or in the shulker barrel looking like an empty one
+        let left = shulker
+            .contents()
+            .ok_or(BotError::ContainerOpen(pos))?
+            .iter()
+            .filter(|slot| slot.count() > 0)
+            .map(|slot| slot.kind().to_string())
+            .collect::<Vec<_>>();
+        drop(shulker);
+        if !left.is_empty() {
+            store_inventory(bot, pool, region).await?;
+            return Err(BotError::Packing(format!(
+                "couldn't take {} out of the {} at the packing station, it's left standing there",
+                left.join(", "),
+                box_id
+            )));
+        }
+        break_box(bot, pool, packing, &box_id).await?;
+        unpacked += 1;
+
+        if let Some(shulker_barrel) = &packing.shulker_barrel {
+            teleport_to(bot, shulker_barrel.x, shulker_barrel.y, shulker_barrel.z)?;
+            put_into_container(bot, shulker_barrel.block_pos(), |item| {
+                is_shulker_box(&item.kind.to_string())
+            })
+            .await?;
+        }
+        let leftover = store_inventory(bot, pool, region).await?;
+        if !leftover.is_empty() {
+            // the next box wouldn't fit either
+            bot.chat(&format!("No room in storage for {}", leftover.join(", ")));
+            break;
+        }
+    }
+    Ok(unpacked)
+}
+
+/// Places a box the bot is holding at the packing station and opens it
+async fn place_box(
+    bot: &mut azalea::Client,
+    packing: &Packing,
+    box_id: &str,
+) -> Result<ContainerHandle, BotError> {
+    let pos = packing.block_pos();
+    teleport_to(bot, packing.x, packing.y, packing.z)?;
+    select_hotbar(bot, box_id)?;
+    bot.look_at(Vec3 {
+        x: pos.x as f64 + 0.5,
+        y: pos.y as f64,
+        z: pos.z as f64 + 0.5,
+    });
+    bot.block_interact(BlockPos {
+        y: pos.y - 1,
+        ..pos
+    });
+    get_storage_handle(bot, pos)
+        .await
+        .ok_or(BotError::ContainerOpen(pos))
+}
+
+/// Breaks the box at the packing station and picks it back up
+async fn break_box(
+    bot: &mut azalea::Client,
+    pool: &PgPool,
+    packing: &Packing,
+    box_id: &str,
+) -> Result<(), BotError> {
+    let pos = packing.block_pos();
+    bot.mine(pos).await;
+    // stand where the box drops so it gets picked up
+    teleport_to(bot, pos.x, pos.y, pos.z)?;
+    tokio::time::sleep(Duration::from_millis(PICKUP_MILLIS)).await;
+    record_inventory(bot, pool).await?;
+    if !holding(bot, box_id) {
+        return Err(BotError::Packing(format!(
+            "the {} wasn't picked up at {:?}",
+            box_id, pos
+        )));
+    }
+    Ok(())
+}
+
+fn holding(bot: &azalea::Client, item_id: &str) -> bool {
+    inventory_item_ids(bot).iter().any(|held| held == item_id)
+}
+
+fn held_count(bot: &azalea::Client, item_id: &str) -> i32 {
+    let menu = bot.menu();
+    let slots = menu.slots();
+    menu.player_slots_range()
+        .filter(|slot| slots[*slot].kind().to_string() == item_id)
+        .map(|slot| slots[slot].count() as i32)
+        .sum()
+}
+
+/// Moves the item into the first hotbar slot if it isn't in the hotbar yet,
+/// and selects the slot it's in
+fn select_hotbar(bot: &mut azalea::Client, item_id: &str) -> Result<(), BotError> {
+    let menu = bot.menu();
+    let slots = menu.slots();
+    let player_slots = menu.player_slots_range();
+    // the hotbar is the last 9 player slots
+    let hotbar_start = player_slots.end - 9;
+    let Some(slot) = player_slots
+        .clone()
+        .find(|slot| slots[*slot].kind().to_string() == item_id)
+    else {
+        return Err(BotError::Packing(format!("not holding any {}", item_id)));
+    };
+    let hotbar_slot = if slot >= hotbar_start {
+        slot - hotbar_start
+    } else {
+        let inventory = bot
+            .open_inventory()
+            .ok_or(BotError::Packing("couldn't open the inventory".to_string()))?;
+        inventory.click(SwapClick {
+            source_slot: slot as u16,
+            target_slot: 0,
+        });
+        0
+    };
+    bot.write_packet(ServerboundGamePacket::SetCarriedItem(
+        ServerboundSetCarriedItemPacket {
+            slot: hotbar_slot as u16,
+        },
+    ));
+    Ok(())
+}
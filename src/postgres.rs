@@ -1,6 +1,10 @@
+use std::collections::HashMap;
+
 use nbt::Blob;
 use sqlx::postgres::PgRow;
 
+use crate::{jobs::Audit, recipes::Recipe};
+
 pub async fn items_in_chest(
     pool: &sqlx::PgPool,
     x: f64,
@@ -15,47 +19,567 @@ pub async fn items_in_chest(
         .await
 }
 
-pub async fn set_item_in_chest(
+/// Tells the `chest_items` history trigger what the rest of the transaction's changes are for
+async fn set_audit(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    audit: &Audit,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT set_config('storage.cause', $1::text, true), set_config('storage.job_id', $2::text, true), set_config('storage.requester', $3::text, true);")
+        .bind(&audit.cause)
+        .bind(audit.job_id.map(|job_id| job_id.to_string()).unwrap_or_default())
+        .bind(audit.requester.clone().unwrap_or_default())
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// Writes every slot of a storage block in one transaction with a single upsert,
+/// so a failure never leaves a barrel half updated. Slots are `(item_id, item_count)`
+/// in slot order
+pub async fn save_chest_contents(
     pool: &sqlx::PgPool,
     x: f64,
     y: f64,
     z: f64,
-    location_in_chest: i32,
-    item_id: &str,
-    item_count: i16,
-    mut item_nbt: Option<Vec<u8>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    if item_nbt.is_none() {
-        let blob: Blob = Blob::new();
-        let mut serialized_nbt: Vec<u8> = vec![];
-        blob.to_writer(&mut serialized_nbt)?;
-        item_nbt = Some(serialized_nbt);
+    slots: &[(String, i16)],
+    audit: &Audit,
+) -> Result<(), sqlx::Error> {
+    let blob: Blob = Blob::new();
+    let mut empty_nbt: Vec<u8> = vec![];
+    blob.to_writer(&mut empty_nbt)
+        .map_err(|err| sqlx::Error::Encode(Box::new(err)))?;
+    let locations = (0..slots.len() as i32).collect::<Vec<_>>();
+    let (item_ids, item_counts): (Vec<String>, Vec<i16>) = slots.iter().cloned().unzip();
+
+    let mut tx = pool.begin().await?;
+    set_audit(&mut tx, audit).await?;
+    sqlx::query("INSERT INTO chests (x, y, z) VALUES ($1::float, $2::float, $3::float) ON CONFLICT (x, y, z) DO NOTHING;")
+        .bind(x)
+        .bind(y)
+        .bind(z)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("INSERT INTO chest_items (x, y, z, location_in_chest, item_id, item_count, item_nbt) SELECT $1::float, $2::float, $3::float, slot.location_in_chest, slot.item_id, slot.item_count, $7::bytea FROM UNNEST($4::int[], $5::text[], $6::smallint[]) AS slot(location_in_chest, item_id, item_count) ON CONFLICT (x, y, z, location_in_chest) DO UPDATE SET item_id = excluded.item_id, item_count = excluded.item_count, item_nbt = excluded.item_nbt;")
+        .bind(x)
+        .bind(y)
+        .bind(z)
+        .bind(locations)
+        .bind(item_ids)
+        .bind(item_counts)
+        .bind(empty_nbt)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+/// Replaces the record of the bot's inventory, slots are `(slot, item_id, item_count)`
+pub async fn save_bot_inventory(
+    pool: &sqlx::PgPool,
+    slots: &[(i32, String, i16)],
+) -> Result<(), sqlx::Error> {
+    let mut locations = vec![];
+    let mut item_ids = vec![];
+    let mut item_counts = vec![];
+    for (slot, item_id, item_count) in slots {
+        locations.push(*slot);
+        item_ids.push(item_id.clone());
+        item_counts.push(*item_count);
     }
-    sqlx::query("CALL insert_item_into_chest ($1::float, $2::float, $3::float, $4::int, $5::text, $6::smallint, $7::bytea);")
-        .bind(x as f64)
-        .bind(y as f64)
-        .bind(z as f64)
-        .bind(location_in_chest)
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM bot_inventory;")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("INSERT INTO bot_inventory (slot, item_id, item_count) SELECT * FROM UNNEST($1::int[], $2::text[], $3::smallint[]);")
+        .bind(locations)
+        .bind(item_ids)
+        .bind(item_counts)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+pub async fn bot_inventory_totals(pool: &sqlx::PgPool) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT item_id, SUM(item_count)::BIGINT AS total FROM bot_inventory GROUP BY item_id ORDER BY total DESC;")
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn insert_packed_shulker(
+    pool: &sqlx::PgPool,
+    item_id: &str,
+    item_count: i32,
+    depot: &str,
+    audit: &Audit,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO packed_shulkers (item_id, item_count, depot, job_id, requester) VALUES ($1::text, $2::int, $3::text, $4::bigint, $5::text);")
         .bind(item_id)
         .bind(item_count)
-        .bind(item_nbt)
-        .fetch_optional(pool)
+        .bind(depot)
+        .bind(audit.job_id)
+        .bind(&audit.requester)
+        .execute(pool)
         .await?;
     Ok(())
 }
 
-pub async fn create_chest(pool: &sqlx::PgPool, x: f64, y: f64, z: f64) -> Result<(), sqlx::Error> {
-    sqlx::query("INSERT INTO chests (x, y, z) VALUES ($1::float, $2::float, $3::float) ON CONFLICT (x, y, z) DO NOTHING;")
-        .bind(x as f64)
-        .bind(y as f64)
-        .bind(z as f64)
-        .fetch_optional(pool).await?;
+/// Shulker boxes the item was packed into, newest first
+pub async fn find_packed(
+    pool: &sqlx::PgPool,
+    item_id: &str,
+    limit: i64,
+) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT item_count, depot, requester, EXTRACT(EPOCH FROM now() - packed_at)::BIGINT AS age_secs FROM packed_shulkers WHERE item_id = $1::text ORDER BY packed_at DESC LIMIT $2::bigint;")
+        .bind(item_id)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+}
+
+/// Keeps the newest `keep` packed boxes recorded at a depot and forgets the rest
+pub async fn forget_packed(pool: &sqlx::PgPool, depot: &str, keep: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM packed_shulkers WHERE depot = $1::text AND packed_id NOT IN (SELECT packed_id FROM packed_shulkers WHERE depot = $1::text ORDER BY packed_at DESC LIMIT $2::bigint);")
+        .bind(depot)
+        .bind(keep)
+        .execute(pool)
+        .await?;
     Ok(())
 }
 
+/// Forgets every storage block, in one transaction so the history shows who did it
+pub async fn clear_storage(pool: &sqlx::PgPool, audit: &Audit) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    set_audit(&mut tx, audit).await?;
+    sqlx::query("DELETE FROM chest_items;")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM chests;").execute(&mut *tx).await?;
+    tx.commit().await
+}
+
 pub async fn find_item(pool: &sqlx::PgPool, item_id: &str) -> Result<Vec<PgRow>, sqlx::Error> {
     sqlx::query("SELECT * FROM find_item($1::text);")
         .bind(item_id)
         .fetch_all(pool)
         .await
 }
+
+pub async fn deposit_candidates(
+    pool: &sqlx::PgPool,
+    item_id: &str,
+    air_id: &str,
+) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT * FROM deposit_candidates($1::text, $2::text);")
+        .bind(item_id)
+        .bind(air_id)
+        .fetch_all(pool)
+        .await
+}
+
+/// Returns false if the chest hasn't been indexed yet
+pub async fn set_chest_filter(
+    pool: &sqlx::PgPool,
+    x: f64,
+    y: f64,
+    z: f64,
+    filter_kind: Option<&str>,
+    filter_value: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query("UPDATE chests SET filter_kind = $4::text, filter_value = $5::text WHERE x = $1::float AND y = $2::float AND z = $3::float;")
+        .bind(x)
+        .bind(y)
+        .bind(z)
+        .bind(filter_kind)
+        .bind(filter_value)
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+pub async fn item_total(pool: &sqlx::PgPool, item_id: &str) -> Result<Option<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT * FROM item_totals WHERE item_id = $1::text;")
+        .bind(item_id)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn item_totals(pool: &sqlx::PgPool, limit: i64) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT * FROM item_totals ORDER BY total DESC LIMIT $1::bigint;")
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn distinct_item_count(pool: &sqlx::PgPool) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("SELECT COUNT(*) FROM item_totals;")
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn storage_fill(pool: &sqlx::PgPool) -> Result<PgRow, sqlx::Error> {
+    sqlx::query("SELECT COUNT(*) AS barrels, COALESCE(SUM(slots), 0)::BIGINT AS slots, COALESCE(SUM(used_slots), 0)::BIGINT AS used_slots FROM chest_fill;")
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn chest_fill(
+    pool: &sqlx::PgPool,
+    x: f64,
+    y: f64,
+    z: f64,
+) -> Result<Option<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT * FROM chest_fill WHERE x = $1::float AND y = $2::float AND z = $3::float;")
+        .bind(x)
+        .bind(y)
+        .bind(z)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Free slots in storage plus how much of the item is stored and in how many stacks
+pub async fn item_capacity(pool: &sqlx::PgPool, item_id: &str) -> Result<PgRow, sqlx::Error> {
+    sqlx::query("SELECT COUNT(*) FILTER (WHERE item_count = 0) AS free_slots, COALESCE(SUM(item_count) FILTER (WHERE item_id = $1::text), 0)::BIGINT AS stored, COUNT(*) FILTER (WHERE item_id = $1::text) AS stacks FROM chest_items;")
+        .bind(item_id)
+        .fetch_one(pool)
+        .await
+}
+
+/// Every stack of an item that is stored in more than one slot, fullest first
+pub async fn mergeable_stacks(pool: &sqlx::PgPool) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT x, y, z, location_in_chest, item_id, item_count, item_nbt FROM chest_items WHERE item_count > 0 AND (item_id, item_nbt) IN (SELECT item_id, item_nbt FROM chest_items WHERE item_count > 0 GROUP BY item_id, item_nbt HAVING COUNT(*) > 1) ORDER BY item_id, item_nbt, item_count DESC;")
+        .fetch_all(pool)
+        .await
+}
+
+/// Replaces every vanilla recipe and item tag, leaving saved patterns alone
+pub async fn replace_vanilla_data(
+    pool: &sqlx::PgPool,
+    recipes: &[Recipe],
+    tags: &HashMap<String, Vec<String>>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM recipes WHERE NOT is_pattern;")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM item_tags;")
+        .execute(&mut *tx)
+        .await?;
+    for recipe in recipes {
+        insert_recipe(&mut tx, recipe).await?;
+    }
+    for (tag, item_ids) in tags {
+        for item_id in item_ids {
+            sqlx::query("INSERT INTO item_tags (tag, item_id) VALUES ($1::text, $2::text) ON CONFLICT DO NOTHING;")
+                .bind(tag)
+                .bind(item_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    tx.commit().await
+}
+
+async fn insert_recipe(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    recipe: &Recipe,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO recipes (recipe_id, station, output_item, output_count, is_pattern) VALUES ($1::text, $2::text, $3::text, $4::smallint, $5::boolean);")
+        .bind(&recipe.recipe_id)
+        .bind(&recipe.station)
+        .bind(&recipe.output_item)
+        .bind(recipe.output_count as i16)
+        .bind(recipe.pattern)
+        .execute(&mut **tx)
+        .await?;
+    for ingredient in &recipe.ingredients {
+        sqlx::query("INSERT INTO recipe_ingredients (recipe_id, slot, choices) VALUES ($1::text, $2::smallint, $3::text[]);")
+            .bind(&recipe.recipe_id)
+            .bind(ingredient.slot as i16)
+            .bind(&ingredient.choices)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn all_recipes(pool: &sqlx::PgPool) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT * FROM recipes;").fetch_all(pool).await
+}
+
+pub async fn all_recipe_ingredients(pool: &sqlx::PgPool) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT * FROM recipe_ingredients ORDER BY recipe_id, slot;")
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn all_item_tags(pool: &sqlx::PgPool) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT * FROM item_tags;")
+        .fetch_all(pool)
+        .await
+}
+
+/// Saves a pattern, replacing any pattern with the same id
+pub async fn save_pattern(pool: &sqlx::PgPool, recipe: &Recipe) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM recipes WHERE recipe_id = $1::text AND is_pattern;")
+        .bind(&recipe.recipe_id)
+        .execute(&mut *tx)
+        .await?;
+    insert_recipe(&mut tx, recipe).await?;
+    tx.commit().await
+}
+
+/// Returns false if there was no such pattern
+pub async fn delete_pattern(pool: &sqlx::PgPool, recipe_id: &str) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query("DELETE FROM recipes WHERE recipe_id = $1::text AND is_pattern;")
+        .bind(recipe_id)
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+pub async fn all_patterns(pool: &sqlx::PgPool) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT * FROM recipes WHERE is_pattern ORDER BY recipe_id;")
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn insert_job(
+    pool: &sqlx::PgPool,
+    requester: &str,
+    command: &str,
+    priority: i16,
+    status: &str,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("INSERT INTO jobs (requester, command, priority, status) VALUES ($1::text, $2::text, $3::smallint, $4::text) RETURNING job_id;")
+        .bind(requester)
+        .bind(command)
+        .bind(priority)
+        .bind(status)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn set_job_running(pool: &sqlx::PgPool, job_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE jobs SET status = 'running', attempts = attempts + 1, started_at = now() WHERE job_id = $1::bigint;")
+        .bind(job_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn set_job_finished(
+    pool: &sqlx::PgPool,
+    job_id: i64,
+    status: &str,
+    result: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE jobs SET status = $2::text, result = $3::text, finished_at = now() WHERE job_id = $1::bigint;")
+        .bind(job_id)
+        .bind(status)
+        .bind(result)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Jobs that were running when the bot stopped get queued again,
+/// unless they've already been tried `max_attempts` times
+pub async fn requeue_interrupted_jobs(
+    pool: &sqlx::PgPool,
+    max_attempts: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE jobs SET status = CASE WHEN attempts >= $1::int THEN 'failed' ELSE 'queued' END, result = CASE WHEN attempts >= $1::int THEN 'interrupted too many times' END, finished_at = CASE WHEN attempts >= $1::int THEN now() END WHERE status = 'running';")
+        .bind(max_attempts)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn unfinished_jobs(pool: &sqlx::PgPool) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT * FROM jobs WHERE status = 'queued' ORDER BY job_id;")
+        .fetch_all(pool)
+        .await
+}
+
+/// Reserves stacks of an item for the job until there are at least `limit` of them,
+/// or every free stack when there's no limit. Slots the job already holds are
+/// returned again, ones held by other jobs are skipped
+pub async fn reserve_item(
+    pool: &sqlx::PgPool,
+    job_id: i64,
+    item_id: &str,
+    limit: Option<i64>,
+) -> Result<Vec<PgRow>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    // two jobs reserving the same item would otherwise both see the same free stacks
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1::text));")
+        .bind(format!("reserve:{}", item_id))
+        .execute(&mut *tx)
+        .await?;
+    let rows = sqlx::query("WITH free AS (SELECT chest_items.x, chest_items.y, chest_items.z, chest_items.location_in_chest, chest_items.item_count, SUM(chest_items.item_count) OVER (ORDER BY chest_items.x, chest_items.y, chest_items.z, chest_items.location_in_chest) - chest_items.item_count AS earlier FROM chest_items JOIN chests ON chests.x = chest_items.x AND chests.y = chest_items.y AND chests.z = chest_items.z WHERE chest_items.item_id = $2::text AND chest_items.item_count > 0 AND NOT EXISTS (SELECT 1 FROM reservations WHERE reservations.x = chest_items.x AND reservations.y = chest_items.y AND reservations.z = chest_items.z AND reservations.location_in_chest = chest_items.location_in_chest AND reservations.job_id <> $1::bigint)), reserved AS (INSERT INTO reservations (job_id, x, y, z, location_in_chest, item_id, item_count) SELECT $1::bigint, x, y, z, location_in_chest, $2::text, item_count FROM free WHERE $3::bigint IS NULL OR earlier < $3::bigint ON CONFLICT (x, y, z, location_in_chest) DO UPDATE SET item_id = excluded.item_id, item_count = excluded.item_count WHERE reservations.job_id = excluded.job_id RETURNING x, y, z, location_in_chest, item_count) SELECT * FROM reserved ORDER BY x, y, z, location_in_chest;")
+        .bind(job_id)
+        .bind(item_id)
+        .bind(limit)
+        .fetch_all(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(rows)
+}
+
+pub async fn release_reservations(pool: &sqlx::PgPool, job_id: i64) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query("DELETE FROM reservations WHERE job_id = $1::bigint;")
+        .bind(job_id)
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected())
+}
+
+/// Nothing is running on startup, so any reservation left is from a crash
+pub async fn clear_reservations(pool: &sqlx::PgPool) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query("DELETE FROM reservations;")
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected())
+}
+
+pub async fn get_job(pool: &sqlx::PgPool, job_id: i64) -> Result<Option<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT job_id, command, requester, status, attempts, result, EXTRACT(EPOCH FROM now() - created_at)::BIGINT AS age_secs FROM jobs WHERE job_id = $1::bigint;")
+        .bind(job_id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Latest changes to slots that held or now hold the item, newest first
+pub async fn item_history(
+    pool: &sqlx::PgPool,
+    item_id: &str,
+    limit: i64,
+) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT *, EXTRACT(EPOCH FROM now() - changed_at)::BIGINT AS age_secs FROM chest_history WHERE old_item_id = $1::text OR new_item_id = $1::text ORDER BY history_id DESC LIMIT $2::bigint;")
+        .bind(item_id)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+}
+
+/// Latest changes made by jobs a user queued, newest first
+pub async fn requester_history(
+    pool: &sqlx::PgPool,
+    requester: &str,
+    limit: i64,
+) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT *, EXTRACT(EPOCH FROM now() - changed_at)::BIGINT AS age_secs FROM chest_history WHERE requester = $1::text ORDER BY history_id DESC LIMIT $2::bigint;")
+        .bind(requester)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+}
+
+/// Deletes history and stock snapshots older than the retention period,
+/// returns how many rows went
+pub async fn prune_history(pool: &sqlx::PgPool, retention_days: i32) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let history = sqlx::query(
+        "DELETE FROM chest_history WHERE changed_at < now() - make_interval(days => $1::int);",
+    )
+    .bind(retention_days)
+    .execute(&mut *tx)
+    .await?;
+    let snapshots = sqlx::query(
+        "DELETE FROM stock_snapshots WHERE taken_at < now() - make_interval(days => $1::int);",
+    )
+    .bind(retention_days)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(history.rows_affected() + snapshots.rows_affected())
+}
+
+/// Saves the current total of every item, including ones that have run out since
+/// the last snapshot so their trend ends at 0
+pub async fn take_stock_snapshot(pool: &sqlx::PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("INSERT INTO stock_snapshots (item_id, total, taken_at) SELECT items.item_id, COALESCE(item_totals.total, 0), now() FROM (SELECT item_id FROM item_totals UNION SELECT item_id FROM stock_snapshots WHERE taken_at = (SELECT MAX(taken_at) FROM stock_snapshots) AND total > 0) AS items LEFT JOIN item_totals ON item_totals.item_id = items.item_id;")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// How an item's total changed over the last `days`: first and last snapshot,
+/// and the fitted change per day
+pub async fn stock_trend(
+    pool: &sqlx::PgPool,
+    item_id: &str,
+    days: i32,
+) -> Result<Option<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT COUNT(*) AS samples, (ARRAY_AGG(total ORDER BY taken_at))[1] AS first_total, (ARRAY_AGG(total ORDER BY taken_at DESC))[1] AS last_total, (regr_slope(total, EXTRACT(EPOCH FROM taken_at)) * 86400)::FLOAT AS per_day FROM stock_snapshots WHERE item_id = $1::text AND taken_at >= now() - make_interval(days => $2::int) HAVING COUNT(*) > 0;")
+        .bind(item_id)
+        .bind(days)
+        .fetch_optional(pool)
+        .await
+}
+
+/// The last snapshot of each day for an item over the last `days`, oldest first
+pub async fn daily_stock(
+    pool: &sqlx::PgPool,
+    item_id: &str,
+    days: i32,
+) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT DISTINCT ON (taken_at::date) taken_at::date::text AS day, total FROM stock_snapshots WHERE item_id = $1::text AND taken_at >= now() - make_interval(days => $2::int) ORDER BY taken_at::date, taken_at DESC;")
+        .bind(item_id)
+        .bind(days)
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn set_stock_alert(
+    pool: &sqlx::PgPool,
+    item_id: &str,
+    threshold: i64,
+    created_by: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO stock_alerts (item_id, threshold, created_by) VALUES ($1::text, $2::bigint, $3::text) ON CONFLICT (item_id) DO UPDATE SET threshold = excluded.threshold, created_by = excluded.created_by, alerting = FALSE;")
+        .bind(item_id)
+        .bind(threshold)
+        .bind(created_by)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn stock_alert_creator(
+    pool: &sqlx::PgPool,
+    item_id: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT created_by FROM stock_alerts WHERE item_id = $1::text;")
+        .bind(item_id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Returns whether there was an alert to delete
+pub async fn delete_stock_alert(pool: &sqlx::PgPool, item_id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM stock_alerts WHERE item_id = $1::text;")
+        .bind(item_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn all_stock_alerts(pool: &sqlx::PgPool) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("SELECT * FROM stock_alerts ORDER BY item_id;")
+        .fetch_all(pool)
+        .await
+}
+
+/// Flips alerts whose item dropped below the threshold, or came back above it by
+/// `hysteresis_percent`, returns the flipped alerts with their new state and current total
+pub async fn update_stock_alerts(
+    pool: &sqlx::PgPool,
+    hysteresis_percent: i64,
+) -> Result<Vec<PgRow>, sqlx::Error> {
+    sqlx::query("WITH current AS (SELECT stock_alerts.item_id, COALESCE(item_totals.total, 0) AS total FROM stock_alerts LEFT JOIN item_totals ON item_totals.item_id = stock_alerts.item_id) UPDATE stock_alerts SET alerting = NOT stock_alerts.alerting FROM current WHERE stock_alerts.item_id = current.item_id AND ((NOT stock_alerts.alerting AND current.total < stock_alerts.threshold) OR (stock_alerts.alerting AND current.total >= stock_alerts.threshold + stock_alerts.threshold * $1::bigint / 100)) RETURNING stock_alerts.item_id, stock_alerts.threshold, stock_alerts.alerting, current.total;")
+        .bind(hysteresis_percent)
+        .fetch_all(pool)
+        .await
+}
@@ -0,0 +1,369 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use serde_json::Value;
+use sqlx::{PgPool, Row};
+
+use crate::{
+    config::StationKind,
+    postgres::{all_item_tags, all_recipe_ingredients, all_recipes},
+};
+
+#[derive(Debug, Clone)]
+pub struct Ingredient {
+    /// Index into the 3x3 crafting grid row by row, or the input slot for other stations
+    pub slot: i32,
+    /// Item ids, or tags prefixed with `#`, any of which can be used
+    pub choices: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Recipe {
+    pub recipe_id: String,
+    pub station: String,
+    pub output_item: String,
+    pub output_count: i32,
+    pub ingredients: Vec<Ingredient>,
+    /// Saved by a player, see `PATTERN_PREFIX`
+    pub pattern: bool,
+}
+
+/// Saved patterns get ids like `pattern:<name>` so they can't clash with vanilla recipes
+pub const PATTERN_PREFIX: &str = "pattern:";
+
+/// Every recipe and item tag in the db, indexed for the crafting planner
+#[derive(Debug, Default)]
+pub struct RecipeBook {
+    by_output: HashMap<String, Vec<Recipe>>,
+    tags: HashMap<String, Vec<String>>,
+}
+
+impl RecipeBook {
+    pub async fn load(pool: &PgPool) -> Result<Self, sqlx::Error> {
+        let mut ingredients: HashMap<String, Vec<Ingredient>> = HashMap::new();
+        for row in all_recipe_ingredients(pool).await? {
+            ingredients
+                .entry(row.get("recipe_id"))
+                .or_default()
+                .push(Ingredient {
+                    slot: row.get::<i16, _>("slot") as i32,
+                    choices: row.get("choices"),
+                });
+        }
+
+        let mut book = RecipeBook::default();
+        for row in all_recipes(pool).await? {
+            let recipe_id: String = row.get("recipe_id");
+            let recipe = Recipe {
+                ingredients: ingredients.remove(&recipe_id).unwrap_or_default(),
+                recipe_id,
+                station: row.get("station"),
+                output_item: row.get("output_item"),
+                output_count: row.get::<i16, _>("output_count") as i32,
+                pattern: row.get("is_pattern"),
+            };
+            book.by_output
+                .entry(recipe.output_item.clone())
+                .or_default()
+                .push(recipe);
+        }
+        // saved patterns get tried first
+        for recipes in book.by_output.values_mut() {
+            recipes.sort_by_key(|recipe| !recipe.pattern);
+        }
+        for row in all_item_tags(pool).await? {
+            book.tags
+                .entry(row.get("tag"))
+                .or_default()
+                .push(row.get("item_id"));
+        }
+        Ok(book)
+    }
+
+    pub fn recipes_for(&self, item_id: &str) -> &[Recipe] {
+        self.by_output
+            .get(item_id)
+            .map(|recipes| recipes.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Best guess at the recipe's button in the stonecutter, assuming it lists every
+    /// vanilla recipe for the input item sorted by output. The server decides the real
+    /// order, so the crafted item gets checked. `None` for pattern-only recipes
+    pub fn stonecutter_button(&self, input_item: &str, output_item: &str) -> Option<u8> {
+        let mut outputs = self
+            .by_output
+            .values()
+            .flatten()
+            .filter(|recipe| recipe.station == "stonecutter" && !recipe.pattern)
+            .filter(|recipe| {
+                recipe.ingredients.iter().any(|ingredient| {
+                    ingredient
+                        .choices
+                        .iter()
+                        .any(|choice| self.expand(choice).iter().any(|id| id == input_item))
+                })
+            })
+            .map(|recipe| recipe.output_item.as_str())
+            .collect::<Vec<_>>();
+        outputs.sort();
+        outputs.dedup();
+        outputs
+            .iter()
+            .position(|output| *output == output_item)
+            .map(|index| index as u8)
+    }
+
+    /// Expands an ingredient choice into the item ids it allows
+    pub fn expand(&self, choice: &str) -> Vec<String> {
+        match choice.strip_prefix('#') {
+            Some(tag) => self.tags.get(tag).cloned().unwrap_or_default(),
+            None => vec![choice.to_string()],
+        }
+    }
+}
+
+/// Reads vanilla recipes and item tags out of a datapack's namespace directory,
+/// like `data/minecraft` from the server jar
+pub fn read_data_dir(
+    path: &Path,
+) -> Result<(Vec<Recipe>, HashMap<String, Vec<String>>), Box<dyn std::error::Error>> {
+    let namespace = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("minecraft");
+
+    let mut recipes = vec![];
+    for entry in fs::read_dir(path.join("recipes"))? {
+        let entry = entry?;
+        let Some(name) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_suffix(".json"))
+            .map(str::to_string)
+        else {
+            continue;
+        };
+        let json: Value = serde_json::from_str(&fs::read_to_string(entry.path())?)?;
+        if let Some(recipe) = parse_recipe(format!("{}:{}", namespace, name), &json) {
+            recipes.push(recipe);
+        }
+    }
+
+    let mut raw_tags: HashMap<String, Vec<String>> = HashMap::new();
+    read_tag_dir(&path.join("tags/items"), namespace, "", &mut raw_tags)?;
+    let tags = raw_tags
+        .keys()
+        .map(|tag| (tag.clone(), flatten_tag(tag, &raw_tags, 0)))
+        .collect();
+
+    Ok((recipes, tags))
+}
+
+fn read_tag_dir(
+    dir: &Path,
+    namespace: &str,
+    prefix: &str,
+    tags: &mut HashMap<String, Vec<String>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type()?.is_dir() {
+            read_tag_dir(
+                &entry.path(),
+                namespace,
+                &format!("{}{}/", prefix, file_name),
+                tags,
+            )?;
+            continue;
+        }
+        let Some(name) = file_name.strip_suffix(".json") else {
+            continue;
+        };
+        let json: Value = serde_json::from_str(&fs::read_to_string(entry.path())?)?;
+        let values = json["values"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|value| match value {
+                Value::String(id) => Some(id.clone()),
+                value => value["id"].as_str().map(str::to_string),
+            })
+            .collect();
+        tags.insert(format!("{}:{}{}", namespace, prefix, name), values);
+    }
+    Ok(())
+}
+
+/// Resolves tags that include other tags into plain item ids
+fn flatten_tag(tag: &str, raw_tags: &HashMap<String, Vec<String>>, depth: usize) -> Vec<String> {
+    let mut item_ids = vec![];
+    if depth > 8 {
+        return item_ids;
+    }
+    for value in raw_tags.get(tag).into_iter().flatten() {
+        match value.strip_prefix('#') {
+            Some(inner) => item_ids.extend(flatten_tag(inner, raw_tags, depth + 1)),
+            None => item_ids.push(value.clone()),
+        }
+    }
+    item_ids
+}
+
+pub fn parse_recipe(recipe_id: String, json: &Value) -> Option<Recipe> {
+    let (output_item, output_count) = parse_result(json)?;
+    let mut station = StationKind::CraftingTable;
+    let ingredients = match json["type"].as_str()? {
+        "minecraft:crafting_shaped" => {
+            let mut ingredients = vec![];
+            for (row, line) in json["pattern"].as_array()?.iter().enumerate() {
+                for (column, key) in line.as_str()?.chars().enumerate() {
+                    if key == ' ' {
+                        continue;
+                    }
+                    ingredients.push(Ingredient {
+                        slot: (row * 3 + column) as i32,
+                        choices: parse_ingredient(&json["key"][key.to_string()]),
+                    });
+                }
+            }
+            ingredients
+        }
+        "minecraft:crafting_shapeless" => json["ingredients"]
+            .as_array()?
+            .iter()
+            .enumerate()
+            .map(|(slot, ingredient)| Ingredient {
+                slot: slot as i32,
+                choices: parse_ingredient(ingredient),
+            })
+            .collect(),
+        "minecraft:stonecutting" => {
+            station = StationKind::Stonecutter;
+            vec![Ingredient {
+                slot: 0,
+                choices: parse_ingredient(&json["ingredient"]),
+            }]
+        }
+        "minecraft:smelting" | "minecraft:blasting" | "minecraft:smoking" => {
+            station = match json["type"].as_str()? {
+                "minecraft:blasting" => StationKind::BlastFurnace,
+                "minecraft:smoking" => StationKind::Smoker,
+                _ => StationKind::Furnace,
+            };
+            vec![Ingredient {
+                slot: 0,
+                choices: parse_ingredient(&json["ingredient"]),
+            }]
+        }
+        "minecraft:smithing_transform" => {
+            station = StationKind::SmithingTable;
+            ["template", "base", "addition"]
+                .iter()
+                .enumerate()
+                .map(|(slot, key)| Ingredient {
+                    slot: slot as i32,
+                    choices: parse_ingredient(&json[*key]),
+                })
+                .collect()
+        }
+        _ => return None,
+    };
+    if ingredients.is_empty()
+        || ingredients
+            .iter()
+            .any(|ingredient| ingredient.choices.is_empty())
+    {
+        return None;
+    }
+
+    Some(Recipe {
+        recipe_id,
+        station: station.as_str().to_string(),
+        output_item,
+        output_count,
+        ingredients,
+        pattern: false,
+    })
+}
+
+fn parse_result(json: &Value) -> Option<(String, i32)> {
+    match &json["result"] {
+        // stonecutting and smelting recipes keep the count next to the result
+        Value::String(item_id) => {
+            Some((item_id.clone(), json["count"].as_i64().unwrap_or(1) as i32))
+        }
+        result => {
+            let item_id = result["item"].as_str().or(result["id"].as_str())?;
+            let count = result["count"].as_i64().unwrap_or(1) as i32;
+            Some((item_id.to_string(), count))
+        }
+    }
+}
+
+fn parse_ingredient(ingredient: &Value) -> Vec<String> {
+    match ingredient {
+        Value::Array(choices) => choices.iter().flat_map(parse_ingredient).collect(),
+        Value::Object(_) => {
+            if let Some(item_id) = ingredient["item"].as_str() {
+                vec![item_id.to_string()]
+            } else if let Some(tag) = ingredient["tag"].as_str() {
+                vec![format!("#{}", tag)]
+            } else {
+                vec![]
+            }
+        }
+        _ => vec![],
+    }
+}
+
+/// Parses a pattern from `<station> <output> <count> <inputs...>`,
+/// where each input fills the next slot and `-` leaves a slot empty
+pub fn parse_pattern(name: &str, args: &[&str]) -> Result<Recipe, String> {
+    let [station, output_item, output_count, inputs @ ..] = args else {
+        return Err("Usage: pattern add <name> <station> <output> <count> <inputs...>".to_string());
+    };
+    let station =
+        StationKind::from_name(station).ok_or_else(|| format!("Unknown station {}", station))?;
+    // the count is stored as a smallint and the planner divides by it
+    let output_count = output_count
+        .parse::<i32>()
+        .ok()
+        .filter(|count| (1..=i16::MAX as i32).contains(count))
+        .ok_or_else(|| {
+            format!(
+                "Invalid count {}, it has to be between 1 and {}. Usage: pattern add <name> <station> <output> <count> <inputs...>",
+                output_count,
+                i16::MAX
+            )
+        })?;
+    if inputs.len() > station.input_count() {
+        return Err(format!(
+            "A {} only has {} input slot(s)",
+            station.as_str(),
+            station.input_count()
+        ));
+    }
+
+    let ingredients = inputs
+        .iter()
+        .enumerate()
+        .filter(|(_, input)| **input != "-")
+        .map(|(slot, input)| Ingredient {
+            slot: slot as i32,
+            choices: input.split('|').map(str::to_string).collect(),
+        })
+        .collect::<Vec<_>>();
+    if ingredients.is_empty() {
+        return Err("A pattern needs at least one input".to_string());
+    }
+
+    Ok(Recipe {
+        recipe_id: format!("{}{}", PATTERN_PREFIX, name),
+        station: station.as_str().to_string(),
+        output_item: output_item.to_string(),
+        output_count,
+        ingredients,
+        pattern: true,
+    })
+}
@@ -0,0 +1,234 @@
+use azalea::registry::Registry;
+use azalea_inventory::item::MaxStackSizeExt;
+use sqlx::{PgPool, Row};
+
+use crate::alerts::run_alert_command;
+use crate::history::{describe_change, describe_trend};
+use crate::jobs::{run_control, JobQueue};
+use crate::postgres::{
+    bot_inventory_totals, chest_fill, distinct_item_count, get_job, item_capacity, item_history,
+    item_total, item_totals, requester_history, storage_fill,
+};
+
+/// Runs commands that only read the db or control the queue, these don't move the bot
+/// so they're answered right away instead of waiting in the queue.
+/// Returns `None` if the command isn't a report
+pub async fn run_report(
+    pool: &PgPool,
+    queue: &JobQueue,
+    bot: &azalea::Client,
+    requester: &str,
+    command_arr: &[&str],
+) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let lines = match command_arr[0] {
+        "alert" => run_alert_command(pool, command_arr, requester).await?,
+        "cancel" | "pause" | "resume" => return run_control(pool, queue, command_arr).await,
+        "queue" => queue.describe(),
+        "status" => {
+            let position = bot.position();
+            vec![format!(
+                "At {:.0} {:.0} {:.0}, {}",
+                position.x,
+                position.y,
+                position.z,
+                queue.summary()
+            )]
+        }
+        "inventory" => {
+            let rows = bot_inventory_totals(pool).await?;
+            if rows.is_empty() {
+                vec!["I'm not carrying anything".to_string()]
+            } else {
+                rows.iter()
+                    .map(|row| {
+                        format!(
+                            "Carrying {}x {}",
+                            row.get::<i64, _>("total"),
+                            row.get::<&str, _>("item_id")
+                        )
+                    })
+                    .collect()
+            }
+        }
+        "total" => {
+            let Some(item_id) = command_arr.get(1) else {
+                return Ok(Some(vec!["Usage: total <item>".to_string()]));
+            };
+            match item_total(pool, item_id).await? {
+                Some(row) => {
+                    let total = row.get::<i64, _>("total");
+                    let reserved = row.get::<i64, _>("reserved");
+                    vec![format!(
+                        "{}x {} in {} stack(s), {} available, {} reserved",
+                        total,
+                        item_id,
+                        row.get::<i64, _>("stacks"),
+                        total - reserved,
+                        reserved
+                    )]
+                }
+                None => vec![format!("No {} in storage", item_id)],
+            }
+        }
+        "totals" => {
+            let mut lines = vec![format!(
+                "{} distinct item(s) in storage",
+                distinct_item_count(pool).await?
+            )];
+            for row in item_totals(pool, 10).await? {
+                let total = row.get::<i64, _>("total");
+                let reserved = row.get::<i64, _>("reserved");
+                lines.push(match reserved {
+                    0 => format!("{}x {}", total, row.get::<&str, _>("item_id")),
+                    _ => format!(
+                        "{}x {} ({} reserved)",
+                        total,
+                        row.get::<&str, _>("item_id"),
+                        reserved
+                    ),
+                });
+            }
+            lines
+        }
+        "fill" => {
+            if command_arr.len() >= 4 {
+                let (Ok(x), Ok(y), Ok(z)) = (
+                    command_arr[1].parse::<f64>(),
+                    command_arr[2].parse::<f64>(),
+                    command_arr[3].parse::<f64>(),
+                ) else {
+                    return Ok(Some(vec!["Usage: fill [<x> <y> <z>]".to_string()]));
+                };
+                match chest_fill(pool, x, y, z).await? {
+                    Some(row) => {
+                        let slots = row.get::<i64, _>("slots");
+                        let used_slots = row.get::<i64, _>("used_slots");
+                        vec![format!(
+                            "({}, {}, {}): {}/{} slots used ({})",
+                            x,
+                            y,
+                            z,
+                            used_slots,
+                            slots,
+                            percent(used_slots, slots)
+                        )]
+                    }
+                    None => vec![format!("No indexed storage block at ({}, {}, {})", x, y, z)],
+                }
+            } else {
+                let row = storage_fill(pool).await?;
+                let slots = row.get::<i64, _>("slots");
+                let used_slots = row.get::<i64, _>("used_slots");
+                vec![format!(
+                    "{} storage block(s), {}/{} slots used ({}), {} free",
+                    row.get::<i64, _>("barrels"),
+                    used_slots,
+                    slots,
+                    percent(used_slots, slots),
+                    slots - used_slots
+                )]
+            }
+        }
+        "capacity" => {
+            let Some(item_id) = command_arr.get(1) else {
+                return Ok(Some(vec!["Usage: capacity <item>".to_string()]));
+            };
+            let Some(item) = item_from_id(item_id) else {
+                return Ok(Some(vec![format!("Unknown item {}", item_id)]));
+            };
+            let max_stack_size = item.max_stack_size() as i64;
+            let row = item_capacity(pool, item_id).await?;
+            let free_slots = row.get::<i64, _>("free_slots");
+            let stored = row.get::<i64, _>("stored");
+            let stacks = row.get::<i64, _>("stacks");
+            // ignores barrel filters, so this is an upper bound
+            let remaining = free_slots * max_stack_size + stacks * max_stack_size - stored;
+            vec![format!(
+                "Room for about {} more {} ({} free slot(s), {} stored)",
+                remaining, item_id, free_slots, stored
+            )]
+        }
+        "trend" => {
+            let Some(item_id) = command_arr.get(1) else {
+                return Ok(Some(vec!["Usage: trend <item> [days]".to_string()]));
+            };
+            let days = command_arr
+                .get(2)
+                .and_then(|days| days.parse::<i32>().ok())
+                .unwrap_or(7);
+            describe_trend(pool, item_id, days).await?
+        }
+        "history" | "audit" => {
+            let Some(subject) = command_arr.get(1) else {
+                return Ok(Some(vec![format!(
+                    "Usage: {} <{}> [count]",
+                    command_arr[0],
+                    if command_arr[0] == "history" {
+                        "item"
+                    } else {
+                        "user"
+                    }
+                )]));
+            };
+            let limit = command_arr
+                .get(2)
+                .and_then(|count| count.parse::<i64>().ok())
+                .unwrap_or(10);
+            let (rows, only_item) = if command_arr[0] == "history" {
+                (item_history(pool, subject, limit).await?, Some(*subject))
+            } else {
+                (requester_history(pool, subject, limit).await?, None)
+            };
+            let lines = rows
+                .iter()
+                .flat_map(|row| describe_change(row, only_item))
+                .collect::<Vec<_>>();
+            if lines.is_empty() {
+                vec![format!("No recorded changes for {}", subject)]
+            } else {
+                lines
+            }
+        }
+        "job" => {
+            let Some(Ok(job_id)) = command_arr
+                .get(1)
+                .map(|id| id.trim_start_matches('#').parse::<i64>())
+            else {
+                return Ok(Some(vec!["Usage: job <id>".to_string()]));
+            };
+            match get_job(pool, job_id).await? {
+                Some(row) => {
+                    let mut line = format!(
+                        "Job #{} ({}) for {}: {}, {} attempt(s), queued {}s ago",
+                        job_id,
+                        row.get::<&str, _>("command"),
+                        row.get::<&str, _>("requester"),
+                        row.get::<&str, _>("status"),
+                        row.get::<i32, _>("attempts"),
+                        row.get::<i64, _>("age_secs")
+                    );
+                    if let Some(result) = row.get::<Option<&str>, _>("result") {
+                        line.push_str(&format!(" ({})", result));
+                    }
+                    vec![line]
+                }
+                None => vec![format!("No job #{}", job_id)],
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(lines))
+}
+
+pub fn item_from_id(item_id: &str) -> Option<azalea::Item> {
+    (0..)
+        .map_while(azalea::Item::from_u32)
+        .find(|item| item.to_string() == item_id)
+}
+
+fn percent(part: i64, whole: i64) -> String {
+    if whole == 0 {
+        return "0%".to_string();
+    }
+    format!("{:.1}%", part as f64 / whole as f64 * 100.0)
+}
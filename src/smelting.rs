@@ -0,0 +1,180 @@
+use std::time::{Duration, Instant};
+
+use azalea_inventory::operations::QuickMoveClick;
+
+use crate::{
+    bot_handle_queue::{get_storage_handle, quick_move, teleport_to},
+    config::{Station, StationKind, CONFIG},
+    crafting::{place_in_slot, CraftStep},
+    error::BotError,
+};
+
+/// Extra time furnaces get on top of the expected cooking time before the bot gives up
+const GRACE_SECS: u64 = 30;
+
+/// Splits a smelting step across every furnace of the right kind, then keeps collecting
+/// the results until everything is smelted. A furnace only takes a stack at a time, so
+/// bigger steps go in rounds. Results end up in the bot's inventory
+pub async fn smelt_step(
+    bot: &mut azalea::Client,
+    kind: StationKind,
+    step: &CraftStep,
+) -> Result<(), BotError> {
+    let furnaces = CONFIG
+        .stations
+        .iter()
+        .filter(|station| station.kind == kind)
+        .collect::<Vec<_>>();
+    if step.inputs.is_empty() || furnaces.is_empty() {
+        return Ok(());
+    }
+
+    let mut remaining = step.times;
+    let mut collected = 0;
+    // the furnaces of the round that fell short, they still hold some of the input
+    let mut unfinished = vec![];
+    while remaining > 0 {
+        let per_furnace = remaining.div_ceil(furnaces.len() as i32);
+        let (loaded, most_loaded) =
+            load_furnaces(bot, kind, step, &furnaces, remaining, per_furnace).await?;
+        let round = loaded.iter().map(|(_, placed)| placed).sum::<i32>();
+        if round == 0 {
+            break;
+        }
+        remaining -= round;
+
+        let expected = round * step.recipe.output_count;
+        let round_collected = collect_results(bot, kind, &loaded, expected, most_loaded).await?;
+        collected += round_collected;
+        // a furnace that isn't keeping up won't do better next round
+        if round_collected < expected {
+            unfinished = loaded;
+            break;
+        }
+    }
+
+    let expected = step.times * step.recipe.output_count;
+    if collected < expected {
+        collected += empty_furnaces(bot, kind, &unfinished).await?;
+    }
+    if collected < expected {
+        return Err(BotError::Craft(format!(
+            "only got {}/{} {} out of the {}s",
+            collected,
+            expected,
+            step.recipe.output_item,
+            kind.as_str()
+        )));
+    }
+    Ok(())
+}
+
+/// Takes everything out of the furnaces, so unsmelted input and fuel go back into
+/// storage with the rest of the inventory. Returns how many results were in them
+async fn empty_furnaces(
+    bot: &mut azalea::Client,
+    kind: StationKind,
+    furnaces: &[(&Station, i32)],
+) -> Result<i32, BotError> {
+    let mut results = 0;
+    for (furnace, _) in furnaces {
+        teleport_to(bot, furnace.x, furnace.y, furnace.z)?;
+        let container = get_storage_handle(bot, furnace.block_pos())
+            .await
+            .ok_or(BotError::ContainerOpen(furnace.block_pos()))?;
+        for slot in [kind.input_slot(0), kind.input_slot(1), kind.result_slot()] {
+            let Some(transfer) = quick_move(&container, slot).await else {
+                return Err(BotError::ContainerOpen(furnace.block_pos()));
+            };
+            if slot == kind.result_slot() {
+                results += transfer.moved;
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Puts up to `per_furnace` of the input and enough fuel in each furnace until
+/// `remaining` are loaded, returns the furnaces that got something and the biggest load
+async fn load_furnaces<'a>(
+    bot: &mut azalea::Client,
+    kind: StationKind,
+    step: &CraftStep,
+    furnaces: &[&'a Station],
+    mut remaining: i32,
+    per_furnace: i32,
+) -> Result<(Vec<(&'a Station, i32)>, i32), BotError> {
+    let Some((_, input)) = step.inputs.first() else {
+        return Ok((vec![], 0));
+    };
+    let mut loaded: Vec<(&Station, i32)> = vec![];
+    let mut most_loaded = 0;
+    for furnace in furnaces {
+        if remaining <= 0 {
+            break;
+        }
+        teleport_to(bot, furnace.x, furnace.y, furnace.z)?;
+        let Some(container) = get_storage_handle(bot, furnace.block_pos()).await else {
+            println!(
+                "failed to open {} at [{:?}]",
+                kind.as_str(),
+                furnace.block_pos()
+            );
+            continue;
+        };
+        let placed = place_in_slot(
+            &container,
+            kind.input_slot(0),
+            input,
+            remaining.min(per_furnace),
+        );
+        if let Some((fuel, _)) = &step.fuel {
+            place_in_slot(
+                &container,
+                kind.input_slot(1),
+                fuel,
+                placed.div_ceil(CONFIG.smelting.items_per_fuel),
+            );
+        }
+        if placed > 0 {
+            remaining -= placed;
+            most_loaded = most_loaded.max(placed);
+            loaded.push((*furnace, placed));
+        }
+    }
+    Ok((loaded, most_loaded))
+}
+
+/// Takes results out of the loaded furnaces until `expected` are collected
+/// or they've had long enough, returns how many were collected
+async fn collect_results(
+    bot: &mut azalea::Client,
+    kind: StationKind,
+    loaded: &[(&Station, i32)],
+    expected: i32,
+    most_loaded: i32,
+) -> Result<i32, BotError> {
+    let deadline =
+        Instant::now() + Duration::from_secs(most_loaded as u64 * kind.cook_secs() + GRACE_SECS);
+    let mut collected = 0;
+    while collected < expected && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_secs(CONFIG.smelting.poll_secs)).await;
+        for (furnace, _) in loaded {
+            teleport_to(bot, furnace.x, furnace.y, furnace.z)?;
+            let Some(container) = get_storage_handle(bot, furnace.block_pos()).await else {
+                continue;
+            };
+            let Some(contents) = container.contents() else {
+                continue;
+            };
+            let done = contents[kind.result_slot()].count() as i32;
+            if done > 0 {
+                container.click(QuickMoveClick::Left {
+                    slot: kind.result_slot() as u16,
+                });
+                collected += done;
+            }
+        }
+    }
+    Ok(collected)
+}